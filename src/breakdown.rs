@@ -0,0 +1,228 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::summary::Stats;
+
+/// A header games can be grouped by in the summary
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Dimension {
+    Variant,
+    Elo,
+    TimeControl,
+    Event,
+    Eco,
+    Opening,
+}
+
+impl Dimension {
+    pub fn from_name(name: &str) -> Option<Dimension> {
+        Some(match name {
+            "variant" => Dimension::Variant,
+            "elo" => Dimension::Elo,
+            "time-control" => Dimension::TimeControl,
+            "event" => Dimension::Event,
+            "eco" => Dimension::Eco,
+            "opening" => Dimension::Opening,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Dimension::Variant => "variant",
+            Dimension::Elo => "elo",
+            Dimension::TimeControl => "time-control",
+            Dimension::Event => "event",
+            Dimension::Eco => "eco",
+            Dimension::Opening => "opening",
+        }
+    }
+
+    /// Whether the groups are reported from the highest il vaticano rate to
+    /// the lowest, rather than by name
+    pub fn ranked(self) -> bool {
+        matches!(self, Dimension::Eco | Dimension::Opening)
+    }
+}
+
+/// Which rating puts a game into an Elo band
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EloBasis {
+    /// The average of both players, so each game is in one band
+    Average,
+    /// The rating of the player to move, so each player's positions count
+    /// towards their own band
+    Mover,
+}
+
+impl EloBasis {
+    pub fn from_name(name: &str) -> Option<EloBasis> {
+        Some(match name {
+            "average" => EloBasis::Average,
+            "mover" => EloBasis::Mover,
+            _ => return None,
+        })
+    }
+}
+
+/// The Elo band a rating falls in, like `1800-1999` for a width of 200
+pub fn elo_band(elo: Option<u32>, width: u32) -> String {
+    match elo {
+        Some(elo) => {
+            let low = elo / width * width;
+            format!("{}-{}", low, low + width - 1)
+        }
+        None => "unrated".to_owned(),
+    }
+}
+
+/// The speed category of a `TimeControl` header, using Lichess' estimate of
+/// the game duration: the initial time plus 40 increments
+pub fn time_control_category(time_control: &str) -> &'static str {
+    if time_control == "-" {
+        return "correspondence";
+    }
+    let Some((initial, increment)) = time_control.split_once('+') else {
+        return "unknown";
+    };
+    let (Ok(initial), Ok(increment)) = (initial.parse::<u32>(), increment.parse::<u32>()) else {
+        return "unknown";
+    };
+    match initial + 40 * increment {
+        0..=179 => "bullet",
+        180..=479 => "blitz",
+        480..=1499 => "rapid",
+        _ => "classical",
+    }
+}
+
+/// The kind of event, without the tournament or swiss link Lichess appends,
+/// like `Rated Blitz tournament`
+pub fn event_type(event: &str) -> &str {
+    let event = match event.find(" http") {
+        Some(link) => &event[..link],
+        None => event,
+    };
+    match event.trim() {
+        "" => "unknown",
+        event => event,
+    }
+}
+
+/// Orders keys that start with a number by that number, like Elo bands,
+/// and everything else alphabetically after them
+fn natural_order(key: &str) -> (bool, u64, &str) {
+    let digits = key.bytes().take_while(u8::is_ascii_digit).count();
+    match key[..digits].parse() {
+        Ok(number) => (false, number, key),
+        Err(_) => (true, 0, key),
+    }
+}
+
+/// Stats of every group, for each dimension games are grouped by
+#[derive(Debug, Default, Clone)]
+pub struct Breakdowns(pub BTreeMap<Dimension, BTreeMap<String, Stats>>);
+
+impl Breakdowns {
+    pub fn add(&mut self, dimension: Dimension, key: &str, stats: &Stats) {
+        let groups = self.0.entry(dimension).or_default();
+        match groups.get_mut(key) {
+            Some(group) => *group += stats,
+            None => {
+                groups.insert(key.to_owned(), stats.clone());
+            }
+        }
+    }
+
+    /// The groups of `dimension` in the order they are reported in
+    pub fn groups(&self, dimension: Dimension) -> Vec<(&str, &Stats)> {
+        let mut groups: Vec<_> = self
+            .0
+            .get(&dimension)
+            .into_iter()
+            .flatten()
+            .map(|(key, stats)| (key.as_str(), stats))
+            .collect();
+        groups.sort_by_key(|&(key, _)| natural_order(key));
+        groups
+    }
+
+    pub fn merge(&mut self, other: &Breakdowns) {
+        for (&dimension, groups) in &other.0 {
+            for (key, stats) in groups {
+                self.add(dimension, key, stats);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bands_elos() {
+        assert_eq!(elo_band(Some(1850), 200), "1800-1999");
+        assert_eq!(elo_band(Some(1800), 200), "1800-1999");
+        assert_eq!(elo_band(Some(1799), 200), "1600-1799");
+        assert_eq!(elo_band(Some(42), 100), "0-99");
+        assert_eq!(elo_band(None, 200), "unrated");
+    }
+
+    #[test]
+    fn orders_groups_by_number() {
+        let mut breakdowns = Breakdowns::default();
+        for key in ["unrated", "1000-1199", "800-999", "2000-2199", "?"] {
+            breakdowns.add(Dimension::Elo, key, &Stats::default());
+        }
+        let keys: Vec<_> = breakdowns
+            .groups(Dimension::Elo)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, ["800-999", "1000-1199", "2000-2199", "?", "unrated"]);
+    }
+
+    #[test]
+    fn categorizes_time_controls() {
+        for (time_control, category) in [
+            ("60+0", "bullet"),
+            ("139+1", "bullet"),
+            ("140+1", "blitz"),
+            ("180+0", "blitz"),
+            ("479+0", "blitz"),
+            ("300+5", "rapid"),
+            ("480+0", "rapid"),
+            ("1499+0", "rapid"),
+            ("1500+0", "classical"),
+            ("900+15", "classical"),
+            ("-", "correspondence"),
+            ("", "unknown"),
+            ("600", "unknown"),
+            ("a+b", "unknown"),
+        ] {
+            assert_eq!(
+                time_control_category(time_control),
+                category,
+                "{time_control}"
+            );
+        }
+    }
+
+    #[test]
+    fn strips_event_links() {
+        assert_eq!(
+            event_type("Rated Blitz tournament https://lichess.org/tournament/abcd1234"),
+            "Rated Blitz tournament"
+        );
+        assert_eq!(
+            event_type("Rated Bullet swiss https://lichess.org/swiss/abcd1234"),
+            "Rated Bullet swiss"
+        );
+        assert_eq!(event_type("Casual Rapid game"), "Casual Rapid game");
+        assert_eq!(event_type(" "), "unknown");
+        assert_eq!(event_type(""), "unknown");
+    }
+}
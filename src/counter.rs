@@ -0,0 +1,850 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs::File,
+    io::{self, BufWriter, Write},
+    sync::Mutex,
+};
+
+use pgn_reader::{RawHeader, SanPlus, Skip, Visitor};
+use serde::Serialize;
+use shakmaty::{
+    fen::Fen,
+    variant::{Variant, VariantPosition},
+    ByColor, CastlingMode, Color, EnPassantMode, Move, Outcome, Position, Role, Square,
+};
+
+use crate::{
+    breakdown::{elo_band, event_type, time_control_category, Breakdowns, Dimension, EloBasis},
+    detector::{Detectors, Event, Game},
+    exchange,
+    export::GameRecord,
+    headers::Headers,
+    options::Options,
+    pattern::{self, IlVaticano, Legality},
+    summary::Stats,
+};
+
+/// One il vaticano position, as written to the `--occurrences` file.
+#[derive(Debug, Serialize)]
+pub struct Occurrence<'a> {
+    site: &'a str,
+    game_id: &'a str,
+    /// Half-moves played before the position was reached
+    ply: usize,
+    turn: &'static str,
+    rank: char,
+    files: [char; 4],
+    fen: String,
+    /// Whether the position is the last one of the game
+    terminal: bool,
+    /// Whether the pattern belongs to the side not to move
+    latent: bool,
+    #[serde(flatten)]
+    legality: Legality,
+    /// Material won after the opponent's best captures, if the move is legal
+    material: Option<i32>,
+}
+
+/// A game that could not be replayed. The rest of its movetext is skipped.
+#[derive(Debug, Clone, Serialize)]
+pub struct InvalidGame {
+    pub headers: Headers,
+    /// Byte offset of the game in the decompressed input
+    pub offset: u64,
+    /// Half-moves played before the bad move
+    pub ply: usize,
+    /// The move that could not be played, if there was one
+    pub san: Option<String>,
+    pub error: String,
+    /// Index of the game within the chunk it was read from
+    #[serde(skip)]
+    pub chunk_game: usize,
+}
+
+/// A game where an il vaticano was played over the board. Bishops can't take
+/// sideways, so this is a bishop taking one of the sandwiched pawns soon after
+/// the pattern was on the board, and another bishop taking the other one soon
+/// after that.
+#[derive(Debug, Clone, Serialize)]
+pub struct PerformedGame {
+    pub headers: Headers,
+    /// Half-moves played before the first and the second capture
+    pub first_ply: usize,
+    pub second_ply: usize,
+    pub color: &'static str,
+    pub squares: [String; 4],
+}
+
+/// The first capture of an il vaticano, waiting for the second one
+#[derive(Debug, Clone, Copy)]
+struct Performing {
+    ply: usize,
+    /// The pawn still to be taken
+    pawn: Square,
+    squares: [Square; 4],
+}
+
+#[derive(Debug)]
+pub struct IlVaticanoCounter<'a> {
+    pub stats: Stats,
+    pub invalid: Vec<InvalidGame>,
+    pub breakdowns: Breakdowns,
+    pub performed: Vec<PerformedGame>,
+    /// Counts for the current game by the side to move, added to `stats` and
+    /// `breakdowns` when it ends
+    game: ByColor<Stats>,
+    pos: VariantPosition,
+    ply: usize,
+    /// Set once a move can't be played, until the next game
+    skip_moves: bool,
+    /// Set when the game doesn't match the filters
+    filtered: bool,
+    /// The last il vaticano of each side to move, with its ply
+    seen: ByColor<Option<(usize, IlVaticano)>>,
+    /// The il vaticano each side is half way through playing
+    performing: ByColor<Option<Performing>>,
+    /// Games begun since the start of the current chunk
+    chunk_games: usize,
+    headers: Headers,
+    options: &'a Options,
+    occurrences: Option<&'a Mutex<BufWriter<File>>>,
+    /// The current game, kept only when exporting
+    record: Option<GameRecord>,
+    export: Option<&'a Mutex<BufWriter<File>>>,
+    detectors: Detectors<'a>,
+    /// The first error writing to any output. Writing stops there, and the
+    /// input fails once the current chunk is counted.
+    error: Option<io::Error>,
+}
+
+/// The files written while counting, shared by all counters
+#[derive(Debug, Default)]
+pub struct Outputs {
+    occurrences: Option<Mutex<BufWriter<File>>>,
+    export: Option<Mutex<BufWriter<File>>>,
+    /// Output streams of detectors by name
+    detectors: BTreeMap<String, Mutex<BufWriter<File>>>,
+}
+
+impl Outputs {
+    pub fn create(options: &Options) -> io::Result<Outputs> {
+        let create =
+            |path: &str| -> io::Result<_> { Ok(Mutex::new(BufWriter::new(File::create(path)?))) };
+        Ok(Outputs {
+            occurrences: options.occurrences.as_deref().map(create).transpose()?,
+            export: options.export_pgn.as_deref().map(create).transpose()?,
+            detectors: options
+                .detector_outputs
+                .iter()
+                .map(|(name, path)| Ok((name.clone(), create(path)?)))
+                .collect::<io::Result<_>>()?,
+        })
+    }
+
+    pub fn flush(self) -> io::Result<()> {
+        for output in self
+            .occurrences
+            .into_iter()
+            .chain(self.export)
+            .chain(self.detectors.into_values())
+        {
+            output.into_inner().expect("writer poisoned").flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> IlVaticanoCounter<'a> {
+    pub fn new(options: &'a Options, outputs: &'a Outputs) -> IlVaticanoCounter<'a> {
+        IlVaticanoCounter {
+            stats: Stats::default(),
+            invalid: Vec::new(),
+            breakdowns: Breakdowns::default(),
+            performed: Vec::new(),
+            game: ByColor::default(),
+            pos: VariantPosition::default(),
+            ply: 0,
+            skip_moves: false,
+            filtered: false,
+            seen: ByColor::default(),
+            performing: ByColor::default(),
+            chunk_games: 0,
+            headers: Headers::default(),
+            options,
+            occurrences: outputs.occurrences.as_ref(),
+            record: outputs.export.as_ref().map(|_| GameRecord::default()),
+            export: outputs.export.as_ref(),
+            detectors: Detectors::new(options, &outputs.detectors),
+            error: None,
+        }
+    }
+
+    fn make_move(&mut self, san: SanPlus) {
+        match san.san.to_move(&self.pos) {
+            Ok(m) => {
+                self.detect(Event::Move(&m));
+                self.check_performed(&m);
+                self.pos.play_unchecked(&m);
+                self.ply += 1;
+            }
+            Err(err) => self.invalidate(Some(&san), &err),
+        }
+    }
+
+    /// Marks the current game as invalid and skips the rest of its moves
+    fn invalidate(&mut self, san: Option<&SanPlus>, error: &dyn Display) {
+        self.skip_moves = true;
+        self.side().invalid_games += 1;
+        self.invalid.push(InvalidGame {
+            headers: self.headers.clone(),
+            offset: 0,
+            ply: self.ply,
+            san: san.map(|san| san.to_string()),
+            error: error.to_string(),
+            chunk_game: self.chunk_games - 1,
+        });
+    }
+
+    /// Resets the game index used to locate invalid games within a chunk
+    pub fn begin_chunk(&mut self) {
+        self.chunk_games = 0;
+    }
+
+    /// Finishes a game the PGN reader gave up on
+    pub fn abort_game(&mut self, error: &io::Error) {
+        if !self.skip_moves {
+            self.invalidate(None, error);
+        }
+        self.finish_game();
+    }
+
+    /// The counts of the current game for the side to move
+    fn side(&mut self) -> &mut Stats {
+        self.game.get_mut(self.pos.turn())
+    }
+
+    /// Adds the counts of the current game to the totals and its groups
+    fn finish_game(&mut self) {
+        if self.filtered {
+            self.stats.filtered_games += 1;
+            return;
+        }
+
+        let mut game = Stats {
+            games: 1,
+            ..Stats::default()
+        };
+        game += &self.game.white;
+        game += &self.game.black;
+        self.stats += &game;
+
+        for &dimension in &self.options.breakdowns {
+            match dimension {
+                Dimension::Variant => {
+                    self.breakdowns
+                        .add(dimension, self.headers.variant(), &game);
+                }
+                Dimension::Elo => self.add_elo_bands(&game),
+                Dimension::TimeControl => {
+                    let key = time_control_category(&self.headers.time_control);
+                    self.breakdowns.add(dimension, key, &game);
+                }
+                Dimension::Event => {
+                    let key = event_type(&self.headers.event);
+                    self.breakdowns.add(dimension, key, &game);
+                }
+                Dimension::Eco | Dimension::Opening => {
+                    let key = match dimension {
+                        Dimension::Eco => &self.headers.eco,
+                        _ => &self.headers.opening,
+                    };
+                    let key = if key.is_empty() { "?" } else { key };
+                    self.breakdowns.add(dimension, key, &game);
+                }
+            }
+        }
+    }
+
+    fn add_elo_bands(&mut self, game: &Stats) {
+        let width = self.options.elo_band;
+        let (white, black) = self.headers.elos();
+        match self.options.elo_by {
+            EloBasis::Average => {
+                let average = white.zip(black).map(|(white, black)| (white + black) / 2);
+                self.breakdowns
+                    .add(Dimension::Elo, &elo_band(average, width), game);
+            }
+            EloBasis::Mover => {
+                let white_band = elo_band(white, width);
+                let black_band = elo_band(black, width);
+                if white_band == black_band {
+                    self.breakdowns.add(Dimension::Elo, &white_band, game);
+                } else {
+                    // The game counts once in the band of each player
+                    for (band, side) in [
+                        (white_band, &self.game.white),
+                        (black_band, &self.game.black),
+                    ] {
+                        let mut stats = side.clone();
+                        stats.games = 1;
+                        self.breakdowns.add(Dimension::Elo, &band, &stats);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn has_error(&self) -> bool {
+        self.error.is_some()
+    }
+
+    /// The first error writing to any output, if there was one
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    /// Keeps the first error writing to an output
+    fn keep_error(&mut self, result: io::Result<()>) {
+        if let Err(err) = result {
+            self.error.get_or_insert(err);
+        }
+    }
+
+    /// Adds the results of another counter to this one
+    pub fn merge(&mut self, mut other: IlVaticanoCounter) {
+        if self.error.is_none() {
+            self.error = other.take_error();
+        }
+        self.stats += &other.stats;
+        self.invalid.extend(other.invalid);
+        self.performed.extend(other.performed);
+        self.breakdowns.merge(&other.breakdowns);
+    }
+
+    /// Counts the current position if it has an il vaticano for the side to
+    /// move, and with `--both-colors` for the other side as well
+    fn check_position(&mut self, terminal: bool) {
+        let turn = self.pos.turn();
+        if self.prefilter(turn) {
+            self.side().passed += 1;
+
+            if let Some(found) = pattern::find(self.pos.board(), turn) {
+                self.side().ilvaticanos += 1;
+                *self.seen.get_mut(turn) = Some((self.ply, found));
+                let (legality, material) = self.evaluate(&found, turn);
+                if legality.legal {
+                    self.side().legal_ilvaticanos += 1;
+                    if material.is_some_and(|change| change > 0) {
+                        self.side().good_ilvaticanos += 1;
+                    }
+                }
+                if let Some(record) = &mut self.record {
+                    record.mark(self.ply, &found, turn);
+                }
+                let written = self.write_occurrence(found, turn, terminal, legality, material);
+                self.keep_error(written);
+            }
+        }
+
+        if self.options.both_colors && self.prefilter(!turn) {
+            if let Some(found) = pattern::find(self.pos.board(), !turn) {
+                self.game.get_mut(!turn).latent_ilvaticanos += 1;
+                // Latent il vaticanos are only evaluated for their occurrences
+                if self.occurrences.is_some() {
+                    let (legality, material) = self.evaluate(&found, !turn);
+                    let written = self.write_occurrence(found, !turn, terminal, legality, material);
+                    self.keep_error(written);
+                }
+            }
+        }
+    }
+
+    /// Whether an il vaticano of `color` could be played, and if so the
+    /// material it wins after the exchanges
+    fn evaluate(&self, found: &IlVaticano, color: Color) -> (Legality, Option<i32>) {
+        let legality = found.legality(self.pos.board(), color);
+        let material = if legality.legal {
+            exchange::material_change(self.pos.board(), color, found)
+        } else {
+            None
+        };
+        (legality, material)
+    }
+
+    /// Cheap test ruling out most positions without an il vaticano for `color`
+    fn prefilter(&self, color: Color) -> bool {
+        pattern::IL_VATICANO.prefilter(self.pos.board(), color)
+    }
+
+    /// Passes an event of the current game to the detectors
+    fn detect(&mut self, event: Event) {
+        let game = Game {
+            pos: &self.pos,
+            ply: self.ply,
+            headers: &self.headers,
+        };
+        let counts = &mut self.game.get_mut(self.pos.turn()).detectors;
+        self.detectors.run(&game, event, counts, &mut self.error);
+    }
+
+    /// Follows the captures of an il vaticano played over the board
+    fn check_performed(&mut self, m: &Move) {
+        let &Move::Normal {
+            role: Role::Bishop,
+            capture: Some(Role::Pawn),
+            to,
+            ..
+        } = m
+        else {
+            return;
+        };
+        let turn = self.pos.turn();
+
+        if let Some(performing) = *self.performing.get(turn) {
+            if self.ply - performing.ply > self.options.performed_within {
+                *self.performing.get_mut(turn) = None;
+            } else if performing.pawn == to {
+                *self.performing.get_mut(turn) = None;
+                self.side().performed_ilvaticanos += 1;
+                self.performed.push(PerformedGame {
+                    headers: self.headers.clone(),
+                    first_ply: performing.ply,
+                    second_ply: self.ply,
+                    color: turn.fold_wb("white", "black"),
+                    squares: performing.squares.map(|square| square.to_string()),
+                });
+                return;
+            }
+        }
+
+        // The first capture takes one of the pawns of the il vaticano the
+        // side to move last had on the board
+        let Some((_, found)) = self
+            .seen
+            .get(turn)
+            .filter(|(ply, _)| self.ply - ply <= self.options.performed_within)
+        else {
+            return;
+        };
+        let pawn = match found.squares {
+            [_, first, second, _] if to == first => second,
+            [_, first, second, _] if to == second => first,
+            _ => return,
+        };
+        *self.performing.get_mut(turn) = Some(Performing {
+            ply: self.ply,
+            pawn,
+            squares: found.squares,
+        });
+    }
+
+    /// Writes an il vaticano for `color` to the `--occurrences` file. It is
+    /// latent unless `color` is to move.
+    fn write_occurrence(
+        &self,
+        found: IlVaticano,
+        color: Color,
+        terminal: bool,
+        legality: Legality,
+        material: Option<i32>,
+    ) -> io::Result<()> {
+        let Some(occurrences) = self.occurrences.filter(|_| self.error.is_none()) else {
+            return Ok(());
+        };
+        let occurrence = Occurrence {
+            site: &self.headers.site,
+            game_id: &self.headers.game_id,
+            ply: self.ply,
+            turn: self.pos.turn().fold_wb("white", "black"),
+            latent: color != self.pos.turn(),
+            legality,
+            material,
+            rank: found.squares[0].rank().char(),
+            files: found.squares.map(|square| square.file().char()),
+            fen: Fen::from_position(self.pos.clone(), EnPassantMode::Legal).to_string(),
+            terminal,
+        };
+        let mut line = serde_json::to_vec(&occurrence).expect("occurrences serialize");
+        line.push(b'\n');
+        occurrences
+            .lock()
+            .expect("occurrence writer poisoned")
+            .write_all(&line)
+    }
+}
+
+impl Visitor for IlVaticanoCounter<'_> {
+    type Result = ();
+    fn begin_game(&mut self) {
+        self.game = ByColor::default();
+        self.ply = 0;
+        self.skip_moves = false;
+        self.filtered = false;
+        self.seen = ByColor::default();
+        self.performing = ByColor::default();
+        self.chunk_games += 1;
+        self.headers.clear();
+        if let Some(record) = &mut self.record {
+            record.clear();
+        }
+    }
+
+    fn header(&mut self, key: &[u8], value: RawHeader<'_>) {
+        if let Some(record) = &mut self.record {
+            record.header(key, &value);
+        }
+        self.headers.set(key, value);
+    }
+
+    fn end_headers(&mut self) -> Skip {
+        if !self.options.filters.matches(&self.headers) {
+            // Nothing is looked at until the next game, not even the final position
+            self.filtered = true;
+            self.skip_moves = true;
+            return Skip(true);
+        }
+
+        let variant = if self.headers.variant.is_empty() || self.headers.chess960() {
+            Variant::Chess
+        } else {
+            match Variant::from_ascii(self.headers.variant.as_bytes()) {
+                Ok(variant) => variant,
+                Err(err) => {
+                    self.invalidate(None, &err);
+                    return Skip(true);
+                }
+            }
+        };
+
+        // `SetUp "0"` explicitly says the FEN is not to be used
+        if !self.headers.fen.is_empty() && self.headers.setup != "0" {
+            let mode = CastlingMode::from_chess960(self.headers.chess960());
+            match Fen::from_ascii(self.headers.fen.as_bytes()) {
+                Ok(fen) => match VariantPosition::from_setup(variant, fen.into_setup(), mode) {
+                    Ok(pos) => self.pos = pos,
+                    Err(err) => self.invalidate(None, &err),
+                },
+                Err(err) => self.invalidate(None, &err),
+            }
+        } else {
+            self.pos = VariantPosition::new(variant);
+        }
+        if let Some(record) = &mut self.record {
+            record.start(&self.pos);
+        }
+        Skip(false)
+    }
+
+    fn san(&mut self, san: SanPlus) {
+        // The whole mainline is exported, even past a move that can't be played
+        if let Some(record) = &mut self.record {
+            record.san(&san);
+        }
+        if self.skip_moves {
+            return;
+        }
+        self.side().sans += 1;
+        self.check_position(false);
+        self.detect(Event::Position { terminal: false });
+        self.make_move(san);
+    }
+
+    fn outcome(&mut self, outcome: Option<Outcome>) {
+        if let Some(record) = &mut self.record {
+            record.outcome(outcome);
+        }
+    }
+
+    fn begin_variation(&mut self) -> Skip {
+        Skip(true) // stay in the mainline
+    }
+
+    fn end_game(&mut self) {
+        if self.options.final_position && !self.skip_moves {
+            self.side().terminal_positions += 1;
+            self.check_position(true);
+            self.detect(Event::Position { terminal: true });
+        }
+        if !self.filtered {
+            self.detect(Event::GameEnd);
+        }
+        if let (Some(record), Some(export)) = (&self.record, self.export) {
+            if record.is_marked() && self.error.is_none() {
+                let written = record.export(export);
+                self.keep_error(written);
+            }
+        }
+        self.finish_game();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, iter, path::PathBuf, process};
+
+    use pgn_reader::BufferedReader;
+
+    use super::*;
+
+    fn options(args: &[&str]) -> Options {
+        Options::parse(
+            args.iter()
+                .map(|arg| arg.to_string())
+                .chain(iter::once("-".into())),
+        )
+        .unwrap()
+    }
+
+    /// A file in the temporary directory, removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> TempFile {
+            TempFile(env::temp_dir().join(format!("ilvaticano-{}-{name}", process::id())))
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+
+        fn read(&self) -> String {
+            fs::read_to_string(&self.0).unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    /// Counts every game of `pgn`
+    fn run<'a>(options: &'a Options, outputs: &'a Outputs, pgn: &str) -> IlVaticanoCounter<'a> {
+        let mut counter = IlVaticanoCounter::new(options, outputs);
+        counter.begin_chunk();
+        BufferedReader::new_cursor(pgn.as_bytes())
+            .read_all(&mut counter)
+            .unwrap();
+        counter
+    }
+
+    #[test]
+    fn reads_chess960_names() {
+        let options = options(&[]);
+        let outputs = Outputs::default();
+        for name in ["Chess960", "CHESS960", "fischerandom", "Fischer Random"] {
+            let pgn = format!(
+                "[Variant \"{name}\"]\n[FEN \"nrbqkbrn/pppppppp/8/8/8/8/PPPPPPPP/NRBQKBRN w GBgb - 0 1\"]\n[SetUp \"1\"]\n\n1. e4 e5 2. Bb5 Bb4 *\n\n"
+            );
+            let counter = run(&options, &outputs, &pgn);
+            assert_eq!(counter.stats.games, 1, "{name}");
+            assert_eq!(counter.stats.invalid_games, 0, "{name}");
+            assert_eq!(counter.stats.sans, 4, "{name}");
+        }
+    }
+
+    #[test]
+    fn replays_variants() {
+        let options = options(&[]);
+        let outputs = Outputs::default();
+        for (variant, moves, sans, invalid) in [
+            // A pawn dropped from the pocket
+            (
+                "Crazyhouse",
+                "1. e4 d5 2. exd5 Qxd5 3. Nc3 Qd8 4. P@d4 *",
+                7,
+                0,
+            ),
+            // The pawn on d5 explodes with the capture, opening the d-file
+            ("Atomic", "1. e4 d5 2. exd5 Qxd2 0-1", 4, 0),
+            // The queen can't pass the pawn, and the move is counted as tried
+            ("Standard", "1. e4 d5 2. exd5 Qxd2 0-1", 4, 1),
+            ("Bughouse", "1. e4 e5 *", 0, 1),
+        ] {
+            let pgn = format!("[Variant \"{variant}\"]\n\n{moves}\n\n");
+            let counter = run(&options, &outputs, &pgn);
+            assert_eq!(counter.stats.sans, sans, "{variant}");
+            assert_eq!(counter.stats.invalid_games, invalid, "{variant}");
+        }
+    }
+
+    const GAMES: &str = r#"[Event "Rated Blitz game"]
+[Site "https://lichess.org/abcdefgh"]
+
+1. e3 c5 2. b3 c4 3. Ba3 d5 4. Bd3 d4 5. Bb4 a6 6. Be4 a5 7. Nf3 h6 1-0
+
+[Event "Casual Rapid game"]
+[Site "https://lichess.org/zzzzzzzz"]
+
+1. e4 e5 2. Nf3 Nc6 0-1
+
+"#;
+
+    #[test]
+    fn ignores_filtered_games() {
+        let occurrences = TempFile::new("filtered.ndjson");
+        let export = TempFile::new("filtered.pgn");
+        let found = TempFile::new("filtered-queries.ndjson");
+        let options = options(&[
+            "--final-position",
+            "--rated",
+            "--occurrences",
+            occurrences.path(),
+            "--export-pgn",
+            export.path(),
+            "--query",
+            "any:wtm or btm",
+            "--detector-output",
+            &format!("queries={}", found.path()),
+        ]);
+        let outputs = Outputs::create(&options).unwrap();
+        let counter = run(&options, &outputs, GAMES);
+        assert_eq!(counter.stats.games, 1);
+        assert_eq!(counter.stats.filtered_games, 1);
+        assert_eq!(counter.stats.ilvaticanos, 2);
+        // Every move of the rated game and its final position
+        assert_eq!(counter.stats.detectors["queries"]["any"], 15);
+        drop(counter);
+        outputs.flush().unwrap();
+
+        let occurrences = occurrences.read();
+        let export = export.read();
+        let found = found.read();
+        assert_eq!(occurrences.lines().count(), 2);
+        assert!(!occurrences.contains("zzzzzzzz"));
+        assert_eq!(export.matches("[Event").count(), 1);
+        assert!(!export.contains("zzzzzzzz"));
+        assert_eq!(found.lines().count(), 15);
+        assert!(!found.contains("zzzzzzzz"));
+    }
+
+    /// Both sides play an il vaticano at the same time, each capture of one
+    /// side followed by one of the other
+    const PERFORMED: &str = r#"[Site "https://lichess.org/perform2"]
+[FEN "4k3/8/8/1bPPb3/1BppB3/8/8/K7 w - - 0 1"]
+[SetUp "1"]
+
+1. Bd3 Bd6 2. Bxc4 Bxc5 3. Bc3 Bc6 4. Bxd4 Bxd5 *
+
+"#;
+
+    #[test]
+    fn finds_performed_ilvaticanos() {
+        let outputs = Outputs::default();
+        let within = options(&[]);
+        let counter = run(&within, &outputs, PERFORMED);
+        let performed: Vec<_> = counter
+            .performed
+            .iter()
+            .map(|game| (game.color, game.first_ply, game.second_ply))
+            .collect();
+        assert_eq!(performed, [("white", 2, 6), ("black", 3, 7)]);
+        assert_eq!(counter.stats.performed_ilvaticanos, 2);
+
+        // The second captures come 4 plies after the first ones
+        let within = options(&["--performed-within", "3"]);
+        let counter = run(&within, &outputs, PERFORMED);
+        assert!(counter.performed.is_empty());
+        let within = options(&["--performed-within", "4"]);
+        let counter = run(&within, &outputs, PERFORMED);
+        assert_eq!(counter.performed.len(), 2);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn keeps_write_errors() {
+        let options = options(&["--final-position", "--occurrences", "/dev/full"]);
+        let outputs = Outputs::create(&options).unwrap();
+        // More occurrences than the writer buffers
+        let mut counter = run(&options, &outputs, &GAMES.repeat(50));
+        assert_eq!(counter.stats.ilvaticanos, 100);
+        assert!(counter.has_error());
+        assert_eq!(
+            counter.take_error().unwrap().kind(),
+            io::ErrorKind::StorageFull
+        );
+    }
+
+    #[test]
+    fn splits_elo_bands_by_mover() {
+        let options = options(&["--by", "elo", "--elo-by", "mover"]);
+        let outputs = Outputs::default();
+        let rated = GAMES.split("\n\n[").next().unwrap();
+        let pgn = format!("[WhiteElo \"1850\"]\n[BlackElo \"1500\"]\n{rated}\n\n");
+        let counter = run(&options, &outputs, &pgn);
+        let groups: Vec<_> = counter
+            .breakdowns
+            .groups(Dimension::Elo)
+            .into_iter()
+            .map(|(key, stats)| (key, stats.games, stats.sans, stats.ilvaticanos))
+            .collect();
+        // The game counts in both bands, with the moves and il vaticanos of
+        // the player in each
+        assert_eq!(groups, [("1400-1599", 1, 7, 0), ("1800-1999", 1, 7, 1)]);
+
+        let pgn = format!("[WhiteElo \"1850\"]\n[BlackElo \"1900\"]\n{rated}\n\n");
+        let counter = run(&options, &outputs, &pgn);
+        let groups: Vec<_> = counter
+            .breakdowns
+            .groups(Dimension::Elo)
+            .into_iter()
+            .map(|(key, stats)| (key, stats.games, stats.sans, stats.ilvaticanos))
+            .collect();
+        assert_eq!(groups, [("1800-1999", 1, 14, 1)]);
+    }
+
+    #[test]
+    fn counts_latent_ilvaticanos() {
+        let occurrences = TempFile::new("latent.ndjson");
+        let options = options(&[
+            "--both-colors",
+            "--by",
+            "elo",
+            "--elo-by",
+            "mover",
+            "--occurrences",
+            occurrences.path(),
+        ]);
+        let outputs = Outputs::create(&options).unwrap();
+        let rated = GAMES.split("\n\n[").next().unwrap();
+        let pgn = format!("[WhiteElo \"1850\"]\n[BlackElo \"1500\"]\n{rated}\n\n");
+        let counter = run(&options, &outputs, &pgn);
+        // White's bishops are in place with Black to move after 6. Be4 and 7. Nf3
+        assert_eq!(counter.stats.ilvaticanos, 1);
+        assert_eq!(counter.stats.latent_ilvaticanos, 2);
+        let latent: Vec<_> = counter
+            .breakdowns
+            .groups(Dimension::Elo)
+            .into_iter()
+            .map(|(key, stats)| (key, stats.latent_ilvaticanos))
+            .collect();
+        assert_eq!(latent, [("1400-1599", 0), ("1800-1999", 2)]);
+        drop(counter);
+        outputs.flush().unwrap();
+
+        let lines: Vec<serde_json::Value> = occurrences
+            .read()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let latent: Vec<_> = lines
+            .iter()
+            .map(|line| {
+                (
+                    line["ply"].as_u64().unwrap(),
+                    line["turn"].as_str().unwrap(),
+                    line["latent"].as_bool().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            latent,
+            [
+                (11, "black", true),
+                (12, "white", false),
+                (13, "black", true)
+            ]
+        );
+    }
+}
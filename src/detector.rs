@@ -0,0 +1,267 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    sync::Mutex,
+};
+
+use serde::Serialize;
+use shakmaty::{fen::Fen, variant::VariantPosition, EnPassantMode, Move, Position};
+
+use crate::{headers::Headers, options::Options, pattern::Sandwich, query::Query};
+
+/// Counts kept by one detector, by name
+pub type Counts = BTreeMap<String, usize>;
+
+/// The game being replayed, as seen by detectors
+pub struct Game<'a> {
+    pub pos: &'a VariantPosition,
+    /// Half-moves played before the current position
+    pub ply: usize,
+    pub headers: &'a Headers,
+}
+
+impl Game<'_> {
+    pub fn fen(&self) -> String {
+        Fen::from_position(self.pos.clone(), EnPassantMode::Legal).to_string()
+    }
+}
+
+/// Where a detector puts what it finds: its own counts in the summary, and
+/// optionally its own NDJSON file
+pub struct Sink<'a> {
+    name: &'static str,
+    counts: &'a mut BTreeMap<String, Counts>,
+    output: Option<&'a Mutex<BufWriter<File>>>,
+    /// The first error writing to any detector's output stream
+    error: &'a mut Option<io::Error>,
+}
+
+impl Sink<'_> {
+    pub fn count(&mut self, key: &str) {
+        let counts = match self.counts.get_mut(self.name) {
+            Some(counts) => counts,
+            None => self.counts.entry(self.name.to_owned()).or_default(),
+        };
+        match counts.get_mut(key) {
+            Some(count) => *count += 1,
+            None => {
+                counts.insert(key.to_owned(), 1);
+            }
+        }
+    }
+
+    /// Whether the detector has an output stream, to skip building records
+    pub fn has_output(&self) -> bool {
+        self.output.is_some()
+    }
+
+    /// Writes one JSON line to the detector's output stream, if it has one.
+    /// Once a write has failed, nothing more is written.
+    pub fn write(&mut self, record: &impl Serialize) {
+        let Some(output) = self.output.filter(|_| self.error.is_none()) else {
+            return;
+        };
+        let mut line = serde_json::to_vec(record).expect("detector records serialize");
+        line.push(b'\n');
+        if let Err(err) = output
+            .lock()
+            .expect("detector writer poisoned")
+            .write_all(&line)
+        {
+            *self.error = Some(err);
+        }
+    }
+}
+
+/// Something counted over the same pass as il vaticano. One `Visitor` drives
+/// every registered detector, and each counting thread has its own.
+///
+/// Il vaticano itself is deliberately not a detector: its legality, exchanges,
+/// latent patterns and captures over the board feed the fixed fields of the
+/// summary, the occurrences and the PGN export.
+pub trait Detector: Send {
+    /// A position a move is about to be played from, or with `terminal` the
+    /// last position of the game
+    fn on_position(&mut self, _game: &Game, _terminal: bool, _sink: &mut Sink) {}
+
+    /// A move about to be played from the current position
+    fn on_move(&mut self, _game: &Game, _m: &Move, _sink: &mut Sink) {}
+
+    fn on_game_end(&mut self, _game: &Game, _sink: &mut Sink) {}
+}
+
+/// Builds a detector if the options ask for it
+type Register = for<'a> fn(&'a Options) -> Option<Box<dyn Detector + 'a>>;
+
+/// Every detector, by the name of its counts in the summary and of its
+/// `--detector-output`
+const REGISTRY: [(&str, Register); 2] = [
+    ("patterns", Patterns::register),
+    ("queries", Queries::register),
+];
+
+/// The names of all detectors, in the order of their CSV columns
+pub fn names() -> impl Iterator<Item = &'static str> {
+    REGISTRY.iter().map(|&(name, _)| name)
+}
+
+/// Whether there is a detector called `name`
+pub fn exists(name: &str) -> bool {
+    names().any(|registered| registered == name)
+}
+
+/// A match written to a detector's output stream
+#[derive(Serialize)]
+struct Found<'a> {
+    site: &'a str,
+    game_id: &'a str,
+    ply: usize,
+    name: &'a str,
+    fen: String,
+}
+
+impl<'a> Found<'a> {
+    fn new(game: &'a Game, name: &'a str) -> Found<'a> {
+        Found {
+            site: &game.headers.site,
+            game_id: &game.headers.game_id,
+            ply: game.ply,
+            name,
+            fen: game.fen(),
+        }
+    }
+}
+
+/// Counts the `--pattern`s for the side to move
+struct Patterns<'a> {
+    sandwiches: &'a [Sandwich],
+}
+
+impl Patterns<'_> {
+    fn register(options: &Options) -> Option<Box<dyn Detector + '_>> {
+        if options.patterns.is_empty() {
+            return None;
+        }
+        Some(Box::new(Patterns {
+            sandwiches: &options.patterns,
+        }))
+    }
+}
+
+impl Detector for Patterns<'_> {
+    fn on_position(&mut self, game: &Game, _terminal: bool, sink: &mut Sink) {
+        let board = game.pos.board();
+        let turn = game.pos.turn();
+        for sandwich in self.sandwiches {
+            if sandwich.prefilter(board, turn) && sandwich.find(board, turn).is_some() {
+                sink.count(sandwich.name());
+                if sink.has_output() {
+                    sink.write(&Found::new(game, sandwich.name()));
+                }
+            }
+        }
+    }
+}
+
+/// Counts the positions matching each `--query`
+struct Queries<'a> {
+    queries: &'a [(String, Query)],
+}
+
+impl Queries<'_> {
+    fn register(options: &Options) -> Option<Box<dyn Detector + '_>> {
+        if options.queries.is_empty() {
+            return None;
+        }
+        Some(Box::new(Queries {
+            queries: &options.queries,
+        }))
+    }
+
+    fn check(&self, game: &Game, m: Option<&Move>, sink: &mut Sink) {
+        for (name, query) in self.queries {
+            if query.matches(game.pos, m) {
+                sink.count(name);
+                if sink.has_output() {
+                    sink.write(&Found::new(game, name));
+                }
+            }
+        }
+    }
+}
+
+impl Detector for Queries<'_> {
+    /// Positions with a move are matched in `on_move`, to know the move
+    fn on_position(&mut self, game: &Game, terminal: bool, sink: &mut Sink) {
+        if terminal {
+            self.check(game, None, sink);
+        }
+    }
+
+    fn on_move(&mut self, game: &Game, m: &Move, sink: &mut Sink) {
+        self.check(game, Some(m), sink);
+    }
+}
+
+/// Runs the hooks of every detector with its own sink
+pub struct Detectors<'a> {
+    detectors: Vec<(&'static str, Box<dyn Detector + 'a>)>,
+    outputs: &'a BTreeMap<String, Mutex<BufWriter<File>>>,
+}
+
+impl fmt::Debug for Detectors<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.detectors.iter().map(|(name, _)| name))
+            .finish()
+    }
+}
+
+/// What happened in the game
+pub enum Event<'m> {
+    Position { terminal: bool },
+    Move(&'m Move),
+    GameEnd,
+}
+
+impl<'a> Detectors<'a> {
+    /// The registered detectors the options ask for
+    pub fn new(
+        options: &'a Options,
+        outputs: &'a BTreeMap<String, Mutex<BufWriter<File>>>,
+    ) -> Detectors<'a> {
+        Detectors {
+            detectors: REGISTRY
+                .iter()
+                .filter_map(|&(name, register)| Some((name, register(options)?)))
+                .collect(),
+            outputs,
+        }
+    }
+
+    /// Passes the event to every detector, counting into `counts` and keeping
+    /// the first error writing to an output stream in `error`
+    pub fn run(
+        &mut self,
+        game: &Game,
+        event: Event,
+        counts: &mut BTreeMap<String, Counts>,
+        error: &mut Option<io::Error>,
+    ) {
+        for (name, detector) in &mut self.detectors {
+            let mut sink = Sink {
+                name,
+                counts,
+                output: self.outputs.get(*name),
+                error,
+            };
+            match event {
+                Event::Position { terminal } => detector.on_position(game, terminal, &mut sink),
+                Event::Move(m) => detector.on_move(game, m, &mut sink),
+                Event::GameEnd => detector.on_game_end(game, &mut sink),
+            }
+        }
+    }
+}
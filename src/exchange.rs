@@ -0,0 +1,90 @@
+use shakmaty::{
+    Board, CastlingMode, Chess, Color, FromSetup, Position, PositionError, Role, Setup,
+};
+
+use crate::pattern::IlVaticano;
+
+/// Captures looked at after an il vaticano, enough to settle exchanges on the
+/// squares of the pattern
+const MAX_DEPTH: u32 = 8;
+
+/// Piece values in pawns. Kings are never captured in legal play.
+fn value(role: Role) -> i32 {
+    match role {
+        Role::Pawn => 1,
+        Role::Knight | Role::Bishop => 3,
+        Role::Rook => 5,
+        Role::Queen => 9,
+        Role::King => 0,
+    }
+}
+
+/// Material of `color` minus the material of the other side
+fn balance(board: &Board, color: Color) -> i32 {
+    board
+        .clone()
+        .into_iter()
+        .map(|(_, piece)| value(piece.role) * if piece.color == color { 1 } else { -1 })
+        .sum()
+}
+
+/// Capture-only search from the side to move's point of view. It may stand
+/// pat instead of capturing.
+fn quiescence(pos: &Chess, mut alpha: i32, beta: i32, depth: u32) -> i32 {
+    let stand_pat = balance(pos.board(), pos.turn());
+    if depth == 0 || stand_pat >= beta {
+        return stand_pat;
+    }
+    alpha = alpha.max(stand_pat);
+    for m in pos.capture_moves() {
+        let mut after = pos.clone();
+        after.play_unchecked(&m);
+        let score = -quiescence(&after, -beta, -alpha, depth - 1);
+        if score >= beta {
+            return score;
+        }
+        alpha = alpha.max(score);
+    }
+    alpha
+}
+
+/// Material won by `color` with the il vaticano, once the opponent has made
+/// the captures that pay off for them. `None` if the move would leave the
+/// king attacked, or the board isn't a regular chess position.
+pub fn material_change(board: &Board, color: Color, found: &IlVaticano) -> Option<i32> {
+    let setup = Setup {
+        board: found.played(board, color),
+        turn: !color,
+        ..Setup::empty()
+    };
+    let pos = Chess::from_setup(setup, CastlingMode::Standard)
+        .or_else(PositionError::ignore_impossible_check)
+        .or_else(PositionError::ignore_too_much_material)
+        .ok()?;
+    let after = -quiescence(&pos, -i32::MAX, i32::MAX, MAX_DEPTH);
+    Some(after - balance(board, color))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pattern;
+
+    fn change(fen: &str) -> Option<i32> {
+        let board = Board::from_ascii_board_fen(fen.as_bytes()).unwrap();
+        let found = pattern::find(&board, Color::White).unwrap();
+        material_change(&board, Color::White, &found)
+    }
+
+    #[test]
+    fn evaluates_exchanges() {
+        assert_eq!(change("4k3/8/8/8/1BppB3/8/8/4K3"), Some(2));
+        // The knight can take a bishop, but would be taken back
+        assert_eq!(change("4k3/8/8/1n6/1BppB3/4P3/8/4K3"), Some(2));
+        assert_eq!(change("4k3/8/8/1n6/1BppB3/8/8/4K3"), Some(-1));
+        // The pawn takes a bishop for free
+        assert_eq!(change("4k3/8/8/2p5/1BppB3/8/8/4K3"), Some(-1));
+        // The bishop on e4 is pinned
+        assert_eq!(change("b3k3/8/8/8/1BppB3/8/8/7K"), None);
+    }
+}
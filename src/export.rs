@@ -0,0 +1,218 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    sync::Mutex,
+};
+
+use pgn_reader::{RawHeader, SanPlus};
+use shakmaty::{Color, Outcome, Position};
+
+use crate::pattern::IlVaticano;
+
+/// Longest line written, as recommended by the PGN standard
+const LINE_LENGTH: usize = 79;
+
+/// The mainline of the current game, kept to write it to the `--export-pgn`
+/// file if it has an il vaticano
+#[derive(Debug, Default)]
+pub struct GameRecord {
+    /// Header keys and values, still escaped as in the input
+    headers: Vec<(String, String)>,
+    /// Move number of the starting position
+    fullmoves: u32,
+    /// Whether Black moves first
+    black_first: bool,
+    sans: Vec<String>,
+    /// Comments by the number of moves played before them
+    comments: Vec<(usize, String)>,
+    /// The result at the end of the movetext, for games without a `Result`
+    outcome: Option<Outcome>,
+}
+
+impl GameRecord {
+    pub fn clear(&mut self) {
+        self.headers.clear();
+        self.sans.clear();
+        self.comments.clear();
+        self.outcome = None;
+    }
+
+    pub fn header(&mut self, key: &[u8], value: &RawHeader<'_>) {
+        self.headers.push((
+            String::from_utf8_lossy(key).into_owned(),
+            String::from_utf8_lossy(value.as_bytes()).into_owned(),
+        ));
+    }
+
+    pub fn start(&mut self, pos: &impl Position) {
+        self.fullmoves = pos.fullmoves().get();
+        self.black_first = pos.turn() == Color::Black;
+    }
+
+    pub fn san(&mut self, san: &SanPlus) {
+        self.sans.push(san.to_string());
+    }
+
+    pub fn outcome(&mut self, outcome: Option<Outcome>) {
+        self.outcome = outcome;
+    }
+
+    /// Marks the il vaticano found after `ply` moves
+    pub fn mark(&mut self, ply: usize, found: &IlVaticano, turn: Color) {
+        let [left, a, b, right] = found.squares;
+        let side = turn.fold_wb("White", "Black");
+        // Lichess markup: bishops in green, pawns in red, and the captures
+        let markup = format!("[%csl G{left},R{a},R{b},G{right}][%cal G{left}{a},G{right}{b}]");
+        self.comments.push((
+            ply,
+            format!("{markup} il vaticano for {side}: B{left} {a} {b} B{right}"),
+        ));
+    }
+
+    pub fn is_marked(&self) -> bool {
+        !self.comments.is_empty()
+    }
+
+    /// Writes the game as PGN, followed by an empty line
+    pub fn write(&self, out: &mut Vec<u8>) {
+        for (key, value) in &self.headers {
+            out.extend_from_slice(format!("[{key} \"{value}\"]\n").as_bytes());
+        }
+        out.push(b'\n');
+
+        let mut tokens = Vec::new();
+        let mut comments = self.comments.iter().peekable();
+        // Whether the next move needs its number, as at the start or after a comment
+        let mut number = true;
+        for ply in 0..=self.sans.len() {
+            while let Some((_, comment)) = comments.next_if(|(at, _)| *at == ply) {
+                // Split into words so long comments can be wrapped as well,
+                // keeping each `[%...]` command on one line
+                tokens.push("{".to_owned());
+                for word in comment.split(' ') {
+                    match tokens.last_mut() {
+                        Some(last) if last.matches('[').count() > last.matches(']').count() => {
+                            last.push(' ');
+                            last.push_str(word);
+                        }
+                        _ => tokens.push(word.to_owned()),
+                    }
+                }
+                tokens.push("}".to_owned());
+                number = true;
+            }
+            let Some(san) = self.sans.get(ply) else {
+                break;
+            };
+            // Plies counted from the first white move of the starting move number
+            let half = ply + usize::from(self.black_first);
+            let fullmoves = self.fullmoves as usize + half / 2;
+            if half.is_multiple_of(2) {
+                tokens.push(format!("{fullmoves}. {san}"));
+            } else if number {
+                tokens.push(format!("{fullmoves}... {san}"));
+            } else {
+                tokens.push(san.clone());
+            }
+            number = false;
+        }
+        tokens.push(match self.headers.iter().find(|(key, _)| key == "Result") {
+            Some((_, result)) => result.clone(),
+            None => self
+                .outcome
+                .map_or("*".to_owned(), |outcome| outcome.to_string()),
+        });
+
+        let mut line = 0;
+        for token in tokens {
+            if line > 0 && line + 1 + token.len() > LINE_LENGTH {
+                out.push(b'\n');
+                line = 0;
+            } else if line > 0 {
+                out.push(b' ');
+                line += 1;
+            }
+            out.extend_from_slice(token.as_bytes());
+            line += token.len();
+        }
+        out.extend_from_slice(b"\n\n");
+    }
+
+    /// Appends the game to the export file
+    pub fn export(&self, file: &Mutex<BufWriter<File>>) -> io::Result<()> {
+        let mut pgn = Vec::new();
+        self.write(&mut pgn);
+        file.lock().expect("PGN writer poisoned").write_all(&pgn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::{fen::Fen, CastlingMode, Chess, Square};
+
+    use super::*;
+
+    #[test]
+    fn writes_marked_games() {
+        let fen = "r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 3 10";
+        let pos: Chess = fen
+            .parse::<Fen>()
+            .unwrap()
+            .into_position(CastlingMode::Standard)
+            .unwrap();
+
+        let mut record = GameRecord::default();
+        for (key, value) in [("Event", "Test"), ("FEN", fen), ("Result", "0-1")] {
+            record.header(key.as_bytes(), &RawHeader(value.as_bytes()));
+        }
+        record.start(&pos);
+        for san in ["Nf6", "Bb5", "a6", "Ba4"] {
+            record.san(&san.parse().unwrap());
+        }
+        let found = IlVaticano {
+            squares: [Square::B4, Square::C4, Square::D4, Square::E4],
+        };
+        record.mark(2, &found, Color::Black);
+        // The final position, after the last move
+        record.mark(4, &found, Color::Black);
+
+        let mut pgn = Vec::new();
+        record.write(&mut pgn);
+        let pgn = String::from_utf8(pgn).unwrap();
+        assert!(pgn.lines().all(|line| line.len() <= LINE_LENGTH));
+        assert_eq!(
+            pgn,
+            "\
+[Event \"Test\"]
+[FEN \"r1bqkbnr/pppp1ppp/2n5/4p3/4P3/5N2/PPPP1PPP/RNBQKB1R b KQkq - 3 10\"]
+[Result \"0-1\"]
+
+10... Nf6 11. Bb5 { [%csl Gb4,Rc4,Rd4,Ge4][%cal Gb4c4,Ge4d4] il vaticano for
+Black: Bb4 c4 d4 Be4 } 11... a6 12. Ba4 {
+[%csl Gb4,Rc4,Rd4,Ge4][%cal Gb4c4,Ge4d4] il vaticano for Black: Bb4 c4 d4 Be4 }
+0-1
+
+"
+        );
+    }
+
+    #[test]
+    fn ends_with_the_result() {
+        let mut record = GameRecord::default();
+        record.start(&Chess::default());
+        record.san(&"e4".parse().unwrap());
+        let ending = |record: &GameRecord| {
+            let mut pgn = Vec::new();
+            record.write(&mut pgn);
+            String::from_utf8(pgn).unwrap()
+        };
+        assert_eq!(ending(&record), "\n1. e4 *\n\n");
+        record.outcome(Some(Outcome::Decisive {
+            winner: Color::White,
+        }));
+        assert_eq!(ending(&record), "\n1. e4 1-0\n\n");
+        // The header wins over the movetext
+        record.header(b"Result", &RawHeader(b"1/2-1/2"));
+        assert_eq!(ending(&record), "[Result \"1/2-1/2\"]\n\n1. e4 1/2-1/2\n\n");
+    }
+}
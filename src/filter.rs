@@ -0,0 +1,160 @@
+use crate::{breakdown::time_control_category, headers::Headers};
+
+/// Conditions on the headers a game has to meet to be counted
+#[derive(Debug, Default)]
+pub struct Filters {
+    /// Dates as in PGN, `2023.07.01`. Shorter prefixes like `2023.07` compare
+    /// against the same part of the game's date.
+    pub date_from: Option<String>,
+    pub date_to: Option<String>,
+    /// Both players need a rating in this range
+    pub min_elo: Option<u32>,
+    pub max_elo: Option<u32>,
+    /// Speed categories, like `blitz`
+    pub time_controls: Vec<String>,
+    pub variants: Vec<String>,
+    pub terminations: Vec<String>,
+    /// Games where either player has one of these names
+    pub players: Vec<String>,
+    /// Only rated games, or only casual ones
+    pub rated: Option<bool>,
+}
+
+/// Whether `value` is one of `allowed`, ignoring case. An empty list allows everything.
+fn allows(allowed: &[String], value: &str) -> bool {
+    allowed.is_empty() || allowed.iter().any(|a| a.eq_ignore_ascii_case(value))
+}
+
+impl Filters {
+    pub fn matches(&self, headers: &Headers) -> bool {
+        let date = |bound: &str| headers.date.get(..bound.len()).unwrap_or(&headers.date);
+        if let Some(from) = &self.date_from {
+            if date(from) < from.as_str() {
+                return false;
+            }
+        }
+        if let Some(to) = &self.date_to {
+            if date(to) > to.as_str() {
+                return false;
+            }
+        }
+
+        if self.min_elo.is_some() || self.max_elo.is_some() {
+            let (white, black) = headers.elos();
+            let in_range = |elo: Option<u32>| {
+                elo.is_some_and(|elo| {
+                    self.min_elo.is_none_or(|min| elo >= min)
+                        && self.max_elo.is_none_or(|max| elo <= max)
+                })
+            };
+            if !in_range(white) || !in_range(black) {
+                return false;
+            }
+        }
+
+        if !allows(
+            &self.time_controls,
+            time_control_category(&headers.time_control),
+        ) || !allows(&self.variants, headers.variant())
+            || !allows(&self.terminations, &headers.termination)
+        {
+            return false;
+        }
+
+        if !self.players.is_empty()
+            && !allows(&self.players, &headers.white)
+            && !allows(&self.players, &headers.black)
+        {
+            return false;
+        }
+
+        // Other events, like over the board tournaments, are neither
+        match self.rated {
+            Some(true) => headers.event.starts_with("Rated"),
+            Some(false) => headers.event.starts_with("Casual"),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(date: &str, white_elo: &str, black_elo: &str, event: &str) -> Headers {
+        Headers {
+            date: date.to_owned(),
+            white: "alice".to_owned(),
+            black: "bob".to_owned(),
+            white_elo: white_elo.to_owned(),
+            black_elo: black_elo.to_owned(),
+            event: event.to_owned(),
+            ..Headers::default()
+        }
+    }
+
+    #[test]
+    fn matches_dates() {
+        let filters = Filters {
+            date_from: Some("2023.07".to_owned()),
+            date_to: Some("2023.08.15".to_owned()),
+            ..Filters::default()
+        };
+        let matches = |date| filters.matches(&headers(date, "", "", ""));
+        assert!(matches("2023.07.01"));
+        assert!(matches("2023.08.15"));
+        assert!(!matches("2023.06.30"));
+        assert!(!matches("2023.08.16"));
+        // A date shorter than the bound compares as far as it goes
+        assert!(matches("2023.08"));
+        assert!(!matches(""));
+    }
+
+    #[test]
+    fn matches_elos() {
+        let filters = Filters {
+            min_elo: Some(1500),
+            max_elo: Some(2000),
+            ..Filters::default()
+        };
+        let matches = |white, black| filters.matches(&headers("", white, black, ""));
+        assert!(matches("1500", "2000"));
+        assert!(!matches("1499", "1800"));
+        assert!(!matches("1800", "2001"));
+        assert!(!matches("1800", "?"));
+        assert!(!matches("", ""));
+    }
+
+    #[test]
+    fn matches_players_and_events() {
+        let filters = Filters {
+            players: vec!["BOB".to_owned(), "carol".to_owned()],
+            ..Filters::default()
+        };
+        assert!(filters.matches(&headers("", "", "", "")));
+        let filters = Filters {
+            players: vec!["carol".to_owned()],
+            ..Filters::default()
+        };
+        assert!(!filters.matches(&headers("", "", "", "")));
+
+        let rated = Filters {
+            rated: Some(true),
+            ..Filters::default()
+        };
+        let casual = Filters {
+            rated: Some(false),
+            ..Filters::default()
+        };
+        for (event, is_rated, is_casual) in [
+            ("Rated Blitz game", true, false),
+            ("Casual Rapid game", false, true),
+            ("", false, false),
+            ("FIDE World Cup", false, false),
+        ] {
+            let headers = headers("", "", "", event);
+            assert_eq!(rated.matches(&headers), is_rated, "{event}");
+            assert_eq!(casual.matches(&headers), is_casual, "{event}");
+        }
+    }
+}
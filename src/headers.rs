@@ -0,0 +1,126 @@
+use pgn_reader::RawHeader;
+use serde::Serialize;
+
+/// Names of Chess960 in the `Variant` header of various sites, compared
+/// ignoring case
+const CHESS960: [&str; 4] = ["chess960", "chess 960", "fischerandom", "fischer random"];
+
+/// The headers of the current game that are looked at. The strings are
+/// cleared rather than dropped between games to reuse their allocations.
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Headers {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub site: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub game_id: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub event: String,
+    /// `UTCDate`, or `Date` if there is none
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub date: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub white: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub black: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub white_elo: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub black_elo: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub time_control: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub termination: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub variant: String,
+    #[serde(rename = "ECO", skip_serializing_if = "String::is_empty")]
+    pub eco: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub opening: String,
+    /// The `FEN` header, for games that don't start from the standard position
+    #[serde(rename = "FEN", skip_serializing_if = "String::is_empty")]
+    pub fen: String,
+    #[serde(rename = "SetUp", skip_serializing_if = "String::is_empty")]
+    pub setup: String,
+}
+
+impl Headers {
+    pub fn clear(&mut self) {
+        for field in [
+            &mut self.site,
+            &mut self.game_id,
+            &mut self.event,
+            &mut self.date,
+            &mut self.white,
+            &mut self.black,
+            &mut self.white_elo,
+            &mut self.black_elo,
+            &mut self.time_control,
+            &mut self.termination,
+            &mut self.variant,
+            &mut self.eco,
+            &mut self.opening,
+            &mut self.fen,
+            &mut self.setup,
+        ] {
+            field.clear();
+        }
+    }
+
+    pub fn set(&mut self, key: &[u8], value: RawHeader<'_>) {
+        let field = match key {
+            b"Site" => &mut self.site,
+            b"GameId" => &mut self.game_id,
+            b"Event" => &mut self.event,
+            b"UTCDate" => {
+                self.date.clear();
+                &mut self.date
+            }
+            b"Date" if self.date.is_empty() => &mut self.date,
+            b"White" => &mut self.white,
+            b"Black" => &mut self.black,
+            b"WhiteElo" => &mut self.white_elo,
+            b"BlackElo" => &mut self.black_elo,
+            b"TimeControl" => &mut self.time_control,
+            b"Termination" => &mut self.termination,
+            b"Variant" => &mut self.variant,
+            b"ECO" => &mut self.eco,
+            b"Opening" => &mut self.opening,
+            b"FEN" => &mut self.fen,
+            b"SetUp" => &mut self.setup,
+            _ => return,
+        };
+        field.push_str(&value.decode_utf8_lossy());
+    }
+
+    /// The `Variant` header, with games that have none counted as standard
+    pub fn variant(&self) -> &str {
+        if self.variant.is_empty() {
+            "Standard"
+        } else {
+            &self.variant
+        }
+    }
+
+    /// How a game is named in the summary: its `Site`, or else its `GameId`
+    pub fn name(&self) -> &str {
+        if !self.site.is_empty() {
+            &self.site
+        } else if !self.game_id.is_empty() {
+            &self.game_id
+        } else {
+            "?"
+        }
+    }
+
+    /// Whether the game is Chess960, which is played with the rules of chess
+    pub fn chess960(&self) -> bool {
+        CHESS960
+            .iter()
+            .any(|name| self.variant.eq_ignore_ascii_case(name))
+    }
+
+    pub fn elos(&self) -> (Option<u32>, Option<u32>) {
+        (self.white_elo.parse().ok(), self.black_elo.parse().ok())
+    }
+}
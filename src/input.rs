@@ -0,0 +1,148 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    path::Path,
+};
+
+/// Compression formats an input can be in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Plain,
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    /// Recognises a format by its magic bytes
+    fn sniff(magic: &[u8]) -> Option<Compression> {
+        Some(if magic.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if magic.starts_with(b"BZh") {
+            Compression::Bzip2
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else {
+            return None;
+        })
+    }
+
+    fn from_extension(path: &Path) -> Option<Compression> {
+        Some(match path.extension()?.to_str()? {
+            "gz" => Compression::Gzip,
+            "bz2" => Compression::Bzip2,
+            "xz" => Compression::Xz,
+            "zst" => Compression::Zstd,
+            _ => return None,
+        })
+    }
+}
+
+/// Wraps `reader` in the decoder for its compression format.
+/// The magic bytes take precedence, the extension of `path` is only a fallback
+/// for inputs too short to sniff.
+fn decompress<R: BufRead + Send + 'static>(
+    mut reader: R,
+    path: Option<&Path>,
+) -> io::Result<Box<dyn Read + Send>> {
+    let compression = Compression::sniff(reader.fill_buf()?)
+        .or_else(|| path.and_then(Compression::from_extension))
+        .unwrap_or(Compression::Plain);
+
+    Ok(match compression {
+        Compression::Plain => Box::new(reader),
+        Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+        Compression::Bzip2 => Box::new(bzip2::bufread::MultiBzDecoder::new(reader)),
+        Compression::Xz => Box::new(xz2::bufread::XzDecoder::new_multi_decoder(reader)),
+        Compression::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+    })
+}
+
+/// Opens a PGN file, decompressing it if needed. `-` reads from stdin.
+pub fn open(path: &str) -> io::Result<Box<dyn Read + Send>> {
+    if path == "-" {
+        return decompress(BufReader::new(io::stdin()), None);
+    }
+    let file = BufReader::new(File::open(path)?);
+    decompress(file, Some(Path::new(path)))
+}
+
+/// The name an input is reported as in the summary
+pub fn name(path: &str) -> &str {
+    if path == "-" {
+        "<stdin>"
+    } else {
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use super::*;
+
+    const PGN: &[u8] = b"[Event \"Rated Blitz game\"]\n\n1. e4 e5 1-0\n\n";
+
+    fn read(data: Vec<u8>, path: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        decompress(Cursor::new(data), Some(Path::new(path)))
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn decompresses_by_magic_bytes() {
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(PGN).unwrap();
+        let mut bzip2 = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        bzip2.write_all(PGN).unwrap();
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(PGN).unwrap();
+
+        for (data, compression) in [
+            (PGN.to_vec(), None),
+            (gzip.finish().unwrap(), Some(Compression::Gzip)),
+            (bzip2.finish().unwrap(), Some(Compression::Bzip2)),
+            (xz.finish().unwrap(), Some(Compression::Xz)),
+            (zstd::encode_all(PGN, 3).unwrap(), Some(Compression::Zstd)),
+        ] {
+            assert_eq!(Compression::sniff(&data), compression);
+            assert_eq!(read(data.clone(), "games.pgn"), PGN, "{compression:?}");
+            // The magic bytes win over a misleading extension
+            if compression.is_some() {
+                assert_eq!(read(data, "games.pgn.xz"), PGN, "{compression:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_extension() {
+        for (path, compression) in [
+            ("games.pgn.gz", Some(Compression::Gzip)),
+            ("games.pgn.bz2", Some(Compression::Bzip2)),
+            ("games.pgn.xz", Some(Compression::Xz)),
+            ("games.pgn.zst", Some(Compression::Zstd)),
+            ("games.pgn", None),
+            ("games", None),
+        ] {
+            assert_eq!(Compression::from_extension(Path::new(path)), compression);
+        }
+
+        // Too short to sniff, so read as gzip because of the extension
+        assert_eq!(Compression::sniff(&[0x1f]), None);
+        let mut out = Vec::new();
+        assert!(
+            decompress(Cursor::new(vec![0x1f]), Some(Path::new("games.pgn.gz")))
+                .unwrap()
+                .read_to_end(&mut out)
+                .is_err()
+        );
+        assert_eq!(read(vec![0x1f], "games.pgn"), [0x1f]);
+    }
+}
@@ -0,0 +1,203 @@
+use std::{collections::HashSet, fs, io, str::FromStr, thread};
+
+use shakmaty::Role;
+
+use crate::{
+    breakdown::{Dimension, EloBasis},
+    detector,
+    filter::Filters,
+    pattern::{Orientation, Sandwich},
+    query::Query,
+    summary::Format,
+};
+
+/// Command line options
+#[derive(Debug)]
+pub struct Options {
+    pub inputs: Vec<String>,
+    pub occurrences: Option<String>,
+    pub export_pgn: Option<String>,
+    pub format: Format,
+    /// Number of threads counting il vaticanos for each input
+    pub threads: usize,
+    /// Number of inputs processed at the same time
+    pub jobs: usize,
+    /// Also check the position after the last move of each game
+    pub final_position: bool,
+    pub both_colors: bool,
+    /// Plies within which the second capture of a performed il vaticano has to follow
+    pub performed_within: usize,
+    /// Sandwich patterns counted besides il vaticano
+    pub patterns: Vec<Sandwich>,
+    /// Position queries by name
+    pub queries: Vec<(String, Query)>,
+    /// Files for the records of detectors, by detector name
+    pub detector_outputs: Vec<(String, String)>,
+    /// Headers to group the summary by
+    pub breakdowns: Vec<Dimension>,
+    /// Width of the Elo bands
+    pub elo_band: u32,
+    pub elo_by: EloBasis,
+    pub filters: Filters,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Parses a query, named by what comes before a `:` or else by its text
+fn query(text: &str) -> io::Result<(String, Query)> {
+    let (name, query) = match text.split_once(':') {
+        Some((name, query)) => (name.trim(), query),
+        None => (text.trim(), text),
+    };
+    Ok((name.to_owned(), query.parse().map_err(invalid)?))
+}
+
+fn number<T: FromStr>(name: &str, value: String) -> io::Result<T> {
+    value
+        .parse()
+        .map_err(|_| invalid(format!("invalid {} {}", name, value)))
+}
+
+/// Splits a comma separated list of values
+fn list(value: String) -> impl Iterator<Item = String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_owned())
+        .collect::<Vec<_>>()
+        .into_iter()
+}
+
+fn positive<T: FromStr + Default + PartialEq>(name: &str, value: String) -> io::Result<T> {
+    value
+        .parse()
+        .ok()
+        .filter(|n| *n != T::default())
+        .ok_or_else(|| invalid(format!("invalid {} {}", name, value)))
+}
+
+impl Options {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> io::Result<Options> {
+        let mut options = Options {
+            inputs: Vec::new(),
+            occurrences: None,
+            export_pgn: None,
+            format: Format::Text,
+            threads: 0,
+            jobs: 0,
+            final_position: false,
+            both_colors: false,
+            performed_within: 6,
+            patterns: Vec::new(),
+            queries: Vec::new(),
+            detector_outputs: Vec::new(),
+            breakdowns: Vec::new(),
+            elo_band: 200,
+            elo_by: EloBasis::Average,
+            filters: Filters::default(),
+        };
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| invalid(format!("{} needs a value", arg)))
+            };
+            match arg.as_str() {
+                "--occurrences" => options.occurrences = Some(value()?),
+                "--export-pgn" => options.export_pgn = Some(value()?),
+                "--format" => {
+                    let format = value()?;
+                    options.format = Format::from_name(&format)
+                        .ok_or_else(|| invalid(format!("unknown output format {}", format)))?;
+                }
+                "--threads" => options.threads = positive("thread count", value()?)?,
+                "--by" => {
+                    let name = value()?;
+                    let dimension = Dimension::from_name(&name)
+                        .ok_or_else(|| invalid(format!("unknown breakdown {}", name)))?;
+                    if !options.breakdowns.contains(&dimension) {
+                        options.breakdowns.push(dimension);
+                    }
+                }
+                "--elo-band" => options.elo_band = positive("Elo band", value()?)?,
+                "--elo-by" => {
+                    let basis = value()?;
+                    options.elo_by = EloBasis::from_name(&basis)
+                        .ok_or_else(|| invalid(format!("unknown Elo basis {}", basis)))?;
+                }
+                "--date-from" => options.filters.date_from = Some(value()?.replace('-', ".")),
+                "--date-to" => options.filters.date_to = Some(value()?.replace('-', ".")),
+                "--min-elo" => options.filters.min_elo = Some(number("Elo", value()?)?),
+                "--max-elo" => options.filters.max_elo = Some(number("Elo", value()?)?),
+                "--time-control" => options.filters.time_controls.extend(list(value()?)),
+                "--variant" => options.filters.variants.extend(list(value()?)),
+                "--termination" => options.filters.terminations.extend(list(value()?)),
+                "--player" => options.filters.players.push(value()?),
+                "--rated" => options.filters.rated = Some(true),
+                "--casual" => options.filters.rated = Some(false),
+                "--final-position" => options.final_position = true,
+                "--both-colors" => options.both_colors = true,
+                "--pattern" => options.patterns.push(value()?.parse().map_err(invalid)?),
+                "--query" => options.queries.push(query(&value()?)?),
+                "--query-file" => {
+                    let path = value()?;
+                    for line in fs::read_to_string(&path)?.lines() {
+                        let line = line.trim();
+                        if !line.is_empty() && !line.starts_with('#') {
+                            options.queries.push(query(line)?);
+                        }
+                    }
+                }
+                "--detector-output" => {
+                    let value = value()?;
+                    let Some((name, path)) = value.split_once('=') else {
+                        return Err(invalid(format!(
+                            "expected <detector>=<path>, got {}",
+                            value
+                        )));
+                    };
+                    if !detector::exists(name) {
+                        return Err(invalid(format!("unknown detector {}", name)));
+                    }
+                    options
+                        .detector_outputs
+                        .push((name.to_owned(), path.to_owned()));
+                }
+                "--orientations" => {
+                    options
+                        .patterns
+                        .extend(Orientation::ALL[1..].iter().map(|&orientation| {
+                            Sandwich::new(Role::Bishop, Role::Pawn, 2, orientation)
+                        }))
+                }
+                "--performed-within" => {
+                    options.performed_within = positive("--performed-within", value()?)?
+                }
+                "--jobs" => options.jobs = positive("job count", value()?)?,
+                // `-` is stdin, but a misspelt option must not be read as a file
+                _ if arg.starts_with("--") => {
+                    return Err(invalid(format!("unknown option {}", arg)))
+                }
+                _ => options.inputs.push(arg),
+            }
+        }
+
+        // A pattern given twice, e.g. also by `--orientations`, is counted once
+        let mut names = HashSet::new();
+        options
+            .patterns
+            .retain(|sandwich| names.insert(sandwich.name().to_owned()));
+
+        // By default, every input gets its own job and the cores are shared between them
+        let cores = thread::available_parallelism().map_or(1, |n| n.get());
+        if options.jobs == 0 {
+            options.jobs = options.inputs.len().clamp(1, cores);
+        }
+        if options.threads == 0 {
+            options.threads = (cores / options.jobs).max(1);
+        }
+
+        Ok(options)
+    }
+}
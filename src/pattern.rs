@@ -0,0 +1,491 @@
+use std::{str::FromStr, sync::LazyLock};
+
+use serde::Serialize;
+use shakmaty::{Bitboard, Board, Color, File, Rank, Role, Square};
+
+/// Which way the pieces of a sandwich line up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    Rank,
+    File,
+    /// Parallel to a1-h8
+    Diagonal,
+    /// Parallel to a8-h1
+    AntiDiagonal,
+}
+
+impl Orientation {
+    pub const ALL: [Orientation; 4] = [
+        Orientation::Rank,
+        Orientation::File,
+        Orientation::Diagonal,
+        Orientation::AntiDiagonal,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Orientation> {
+        Some(match name {
+            "rank" => Orientation::Rank,
+            "file" => Orientation::File,
+            "diagonal" => Orientation::Diagonal,
+            "anti-diagonal" => Orientation::AntiDiagonal,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Orientation::Rank => "rank",
+            Orientation::File => "file",
+            Orientation::Diagonal => "diagonal",
+            Orientation::AntiDiagonal => "anti-diagonal",
+        }
+    }
+
+    /// The lines the pieces stand on, each from its first square onwards
+    fn lines(self) -> Vec<Vec<Square>> {
+        let line = |start: Square, file_step: i32, rank_step: i32| {
+            let mut squares = vec![start];
+            while let Some(next) = squares.last().and_then(|square| {
+                let file = File::try_from(i32::from(square.file()) + file_step).ok()?;
+                let rank = Rank::try_from(i32::from(square.rank()) + rank_step).ok()?;
+                Some(Square::from_coords(file, rank))
+            }) {
+                squares.push(next);
+            }
+            squares
+        };
+        match self {
+            Orientation::Rank => Rank::ALL
+                .iter()
+                .map(|&rank| line(Square::from_coords(File::A, rank), 1, 0))
+                .collect(),
+            Orientation::File => File::ALL
+                .iter()
+                .map(|&file| line(Square::from_coords(file, Rank::First), 0, 1))
+                .collect(),
+            // Starting from the edge file and the first rank, going up
+            Orientation::Diagonal | Orientation::AntiDiagonal => {
+                let (edge, file_step) = match self {
+                    Orientation::Diagonal => (File::A, 1),
+                    _ => (File::H, -1),
+                };
+                let starts = Rank::ALL
+                    .iter()
+                    .rev()
+                    .map(|&rank| Square::from_coords(edge, rank))
+                    .chain(
+                        File::ALL
+                            .iter()
+                            .filter(|&&file| file != edge)
+                            .map(|&file| Square::from_coords(file, Rank::First)),
+                    );
+                starts.map(|start| line(start, file_step, 1)).collect()
+            }
+        }
+    }
+}
+
+/// Where the pieces of one sandwich are
+#[derive(Debug, Clone)]
+struct Placement {
+    squares: Vec<Square>,
+    outer: Bitboard,
+    inner: Bitboard,
+}
+
+/// Two of our pieces with `count` enemy pieces in a row between them, like
+/// the B-pp-B of il vaticano
+#[derive(Debug, Clone)]
+pub struct Sandwich {
+    pub outer: Role,
+    pub inner: Role,
+    pub count: usize,
+    /// The spec of the pattern, like `B-pp-B`
+    name: String,
+    placements: Vec<Placement>,
+    /// Squares of which every placement has at least one outer piece on
+    outer_mask: Bitboard,
+    /// The lines with at least one placement
+    lines: Vec<Bitboard>,
+}
+
+/// Whether a piece of `role` can ever stand on `square`
+fn can_stand(role: Role, square: Square) -> bool {
+    role != Role::Pawn || !matches!(square.rank(), Rank::First | Rank::Eighth)
+}
+
+impl Sandwich {
+    pub fn new(outer: Role, inner: Role, count: usize, orientation: Orientation) -> Sandwich {
+        let length = count + 2;
+        let mut placements = Vec::new();
+        let mut outer_mask = Bitboard(0);
+        let mut lines = Vec::new();
+        for line in orientation.lines() {
+            let mut any = Bitboard(0);
+            for (start, squares) in line.windows(length).enumerate() {
+                let (first, last) = (squares[0], squares[length - 1]);
+                if !can_stand(outer, first)
+                    || !can_stand(outer, last)
+                    || !squares[1..length - 1]
+                        .iter()
+                        .all(|&square| can_stand(inner, square))
+                {
+                    continue;
+                }
+                // Outer pieces are `length - 1` squares apart, so of the
+                // positions `i` along the line with `i / (length - 1)` odd,
+                // every placement has exactly one
+                for (i, square) in [(start, first), (start + length - 1, last)] {
+                    if (i / (length - 1)) % 2 == 1 {
+                        outer_mask.add(square);
+                    }
+                }
+                let placement = Placement {
+                    squares: squares.to_vec(),
+                    outer: Bitboard::from(first) | Bitboard::from(last),
+                    inner: squares[1..length - 1].iter().copied().collect(),
+                };
+                any |= placement.outer | placement.inner;
+                placements.push(placement);
+            }
+            if any.any() {
+                lines.push(line.iter().copied().collect());
+            }
+        }
+        let pieces = format!(
+            "{}-{}-{}",
+            outer.upper_char(),
+            inner.char().to_string().repeat(count),
+            outer.upper_char()
+        );
+        Sandwich {
+            outer,
+            inner,
+            count,
+            // Along ranks is the default, and left out
+            name: if orientation == Orientation::Rank {
+                pieces
+            } else {
+                format!("{pieces}/{}", orientation.name())
+            },
+            placements,
+            outer_mask,
+            lines,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Cheap test ruling out most positions without the pattern for `color`
+    pub fn prefilter(&self, board: &Board, color: Color) -> bool {
+        let outer = board.by_piece(self.outer.of(color));
+        let inner = board.by_piece(self.inner.of(!color));
+        if (outer & self.outer_mask).is_empty() {
+            return false;
+        }
+        self.lines
+            .iter()
+            .any(|&line| (outer & line).count() >= 2 && (inner & line).count() >= self.count)
+    }
+
+    /// The squares of the first placement of the pattern for `color`, from
+    /// one outer piece to the other
+    pub fn find(&self, board: &Board, color: Color) -> Option<&[Square]> {
+        let outer = board.by_piece(self.outer.of(color));
+        let inner = board.by_piece(self.inner.of(!color));
+        self.placements
+            .iter()
+            .find(|p| outer.is_superset(p.outer) && inner.is_superset(p.inner))
+            .map(|p| p.squares.as_slice())
+    }
+}
+
+impl FromStr for Sandwich {
+    type Err = String;
+
+    /// Parses specs like `B-pp-B` or `R-nnn-R`, optionally followed by
+    /// `/<orientation>`
+    fn from_str(spec: &str) -> Result<Sandwich, String> {
+        let invalid = || format!("invalid pattern {spec}, expected something like B-pp-B");
+        let (pieces, orientation) = match spec.split_once('/') {
+            Some((pieces, orientation)) => (
+                pieces,
+                Orientation::from_name(orientation)
+                    .ok_or_else(|| format!("unknown orientation {orientation}"))?,
+            ),
+            None => (spec, Orientation::Rank),
+        };
+        let [outer, inner, last] = pieces.split('-').collect::<Vec<_>>()[..] else {
+            return Err(invalid());
+        };
+        let role = |name: &str| {
+            let mut chars = name.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Role::from_char(c.to_ascii_lowercase()),
+                _ => None,
+            }
+        };
+        let outer_role = role(outer).ok_or_else(invalid)?;
+        let inner_role = inner
+            .chars()
+            .next()
+            .and_then(|c| Role::from_char(c.to_ascii_lowercase()))
+            .ok_or_else(invalid)?;
+        if !outer.eq_ignore_ascii_case(last)
+            || !inner
+                .chars()
+                .all(|c| c.eq_ignore_ascii_case(&inner_role.char()))
+        {
+            return Err(invalid());
+        }
+        if !(1..=6).contains(&inner.len()) {
+            return Err(format!("pattern {spec} needs 1 to 6 pieces in between"));
+        }
+        Ok(Sandwich::new(
+            outer_role,
+            inner_role,
+            inner.len(),
+            orientation,
+        ))
+    }
+}
+
+/// Il vaticano itself: two bishops around two enemy pawns on a rank
+pub static IL_VATICANO: LazyLock<Sandwich> =
+    LazyLock::new(|| Sandwich::new(Role::Bishop, Role::Pawn, 2, Orientation::Rank));
+
+/// An il vaticano on the board, from the leftmost bishop to the rightmost one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IlVaticano {
+    pub squares: [Square; 4],
+}
+
+/// Finds an il vaticano of `color`'s bishops around two enemy pawns
+pub fn find(board: &Board, color: Color) -> Option<IlVaticano> {
+    IL_VATICANO.find(board, color).map(|squares| IlVaticano {
+        squares: squares.try_into().expect("four squares"),
+    })
+}
+
+/// Whether an il vaticano could be played as a move, with both bishops
+/// capturing the pawn next to them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Legality {
+    /// The side with the bishops is in check before the move
+    pub in_check: bool,
+    /// Its king is not attacked after the move
+    pub legal: bool,
+    /// The other king is attacked after the move
+    pub gives_check: bool,
+}
+
+impl IlVaticano {
+    /// The board after the bishops capture the pawns
+    pub fn played(&self, board: &Board, color: Color) -> Board {
+        let [left, a, b, right] = self.squares;
+        let mut board = board.clone();
+        board.discard_piece_at(left);
+        board.discard_piece_at(right);
+        board.set_piece_at(a, color.bishop());
+        board.set_piece_at(b, color.bishop());
+        board
+    }
+
+    /// Classifies the il vaticano of `color`'s bishops as a move. Boards
+    /// without a king, as in some variants, are never in check.
+    pub fn legality(&self, board: &Board, color: Color) -> Legality {
+        let attacked = |board: &Board, king: Color| {
+            board
+                .king_of(king)
+                .is_some_and(|square| board.attacks_to(square, !king, board.occupied()).any())
+        };
+        let after = self.played(board, color);
+        Legality {
+            in_check: attacked(board, color),
+            legal: !attacked(&after, color),
+            gives_check: attacked(&after, !color),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::{Piece, Rank, Role};
+
+    use super::*;
+
+    /// The detection `IlVaticanoCounter::san` used before the bitboard version
+    fn find_by_fen(board: &Board, color: Color) -> bool {
+        let fen = board.board_fen(Bitboard(0)).to_string();
+        fen.contains(color.fold_wb("BppB", "bPPb"))
+    }
+
+    /// A small xorshift generator, so the boards are the same on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    #[test]
+    fn finds_squares() {
+        let board =
+            Board::from_ascii_board_fen(b"rnbqkbnr/1p2pppp/8/p7/1BppB3/1P2P3/P1PP1PPP/RN1QK1NR")
+                .unwrap();
+        assert_eq!(
+            find(&board, Color::White),
+            Some(IlVaticano {
+                squares: [Square::B4, Square::C4, Square::D4, Square::E4]
+            })
+        );
+        assert_eq!(find(&board, Color::Black), None);
+    }
+
+    #[test]
+    fn derives_il_vaticano_prefilter() {
+        // The D, E, F files without the backranks, as hard-coded before
+        assert_eq!(IL_VATICANO.outer_mask, Bitboard(15824412808329216));
+        assert_eq!(IL_VATICANO.placements.len(), 30);
+        assert_eq!(IL_VATICANO.lines.len(), 6);
+    }
+
+    #[test]
+    fn parses_specs() {
+        let sandwich: Sandwich = "R-nnn-R".parse().unwrap();
+        assert_eq!(
+            (sandwich.outer, sandwich.inner, sandwich.count),
+            (Role::Rook, Role::Knight, 3)
+        );
+        assert_eq!(sandwich.name(), "R-nnn-R");
+        assert_eq!("b-P-b".parse::<Sandwich>().unwrap().name(), "B-p-B");
+        assert_eq!("B-pp-B/rank".parse::<Sandwich>().unwrap().name(), "B-pp-B");
+        assert_eq!(
+            "B-pp-B/file".parse::<Sandwich>().unwrap().name(),
+            "B-pp-B/file"
+        );
+        for spec in [
+            "B-pp-R",
+            "B-pn-B",
+            "B--B",
+            "B-ppppppp-B",
+            "X-pp-X",
+            "B-pp-B/sideways",
+            "B-é-B",
+            "é-p-é",
+            "B-pé-B",
+        ] {
+            assert!(spec.parse::<Sandwich>().is_err(), "{}", spec);
+        }
+
+        // Every placement has an outer piece on the derived mask
+        for spec in [
+            "B-p-B",
+            "B-ppp-B/file",
+            "R-nn-R/diagonal",
+            "Q-pppppp-Q/anti-diagonal",
+        ] {
+            let sandwich: Sandwich = spec.parse().unwrap();
+            assert!(sandwich
+                .placements
+                .iter()
+                .all(|p| (p.outer & sandwich.outer_mask).any()));
+        }
+    }
+
+    #[test]
+    fn finds_orientations() {
+        for (orientation, fen) in [
+            (Orientation::File, "8/8/8/1B6/1p6/1p6/1B6/8"),
+            (Orientation::Diagonal, "8/8/8/8/3B4/2p5/1p6/B7"),
+            (Orientation::AntiDiagonal, "8/8/8/8/4B3/5p2/6p1/7B"),
+        ] {
+            let board = Board::from_ascii_board_fen(fen.as_bytes()).unwrap();
+            for other in Orientation::ALL {
+                let sandwich = Sandwich::new(Role::Bishop, Role::Pawn, 2, other);
+                let found = sandwich.prefilter(&board, Color::White)
+                    && sandwich.find(&board, Color::White).is_some();
+                assert_eq!(found, other == orientation, "{} on {}", other.name(), fen);
+            }
+        }
+    }
+
+    #[test]
+    fn classifies_legality() {
+        let legality = |fen: &str| {
+            let board = Board::from_ascii_board_fen(fen.as_bytes()).unwrap();
+            find(&board, Color::White)
+                .unwrap()
+                .legality(&board, Color::White)
+        };
+        let legality_of = |in_check, legal, gives_check| Legality {
+            in_check,
+            legal,
+            gives_check,
+        };
+        assert_eq!(
+            legality("4k3/8/8/8/1BppB3/8/8/4K3"),
+            legality_of(false, true, false)
+        );
+        // The bishop on e4 is pinned
+        assert_eq!(
+            legality("b3k3/8/8/8/1BppB3/8/8/7K"),
+            legality_of(false, false, false)
+        );
+        assert_eq!(
+            legality("4k3/8/8/8/1BppB3/8/8/4K2r"),
+            legality_of(true, false, false)
+        );
+        assert_eq!(
+            legality("8/6k1/8/8/1BppB3/8/8/4K3"),
+            legality_of(false, true, true)
+        );
+    }
+
+    #[test]
+    fn matches_fen_detection() {
+        // Boards crowded with bishops and pawns, so that the pattern shows up often
+        const ROLES: [Role; 8] = [
+            Role::Bishop,
+            Role::Bishop,
+            Role::Bishop,
+            Role::Pawn,
+            Role::Pawn,
+            Role::Pawn,
+            Role::Knight,
+            Role::Queen,
+        ];
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let mut found = 0;
+        for _ in 0..50_000 {
+            let mut board = Board::empty();
+            for square in Square::ALL {
+                let roll = rng.next();
+                let role = ROLES[(roll >> 8) as usize % ROLES.len()];
+                let backrank = square.rank() == Rank::First || square.rank() == Rank::Eighth;
+                if roll.is_multiple_of(3) || role == Role::Pawn && backrank {
+                    continue;
+                }
+                let color = Color::from_white(roll >> 16 & 1 == 0);
+                board.set_piece_at(square, Piece { color, role });
+            }
+            for color in Color::ALL {
+                let by_bitboard = find(&board, color);
+                assert_eq!(
+                    by_bitboard.is_some(),
+                    find_by_fen(&board, color),
+                    "{}",
+                    board
+                );
+                found += usize::from(by_bitboard.is_some());
+            }
+        }
+        assert!(found > 300, "only {} boards had the pattern", found);
+    }
+}
@@ -0,0 +1,369 @@
+use std::{
+    io::{self, Read},
+    iter, mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Mutex,
+    },
+    thread,
+    time::Instant,
+};
+
+use pgn_reader::BufferedReader;
+
+use crate::{counter::IlVaticanoCounter, summary::Stats};
+
+/// How much decompressed data is read at once
+const BLOCK_SIZE: usize = 1 << 20;
+
+/// Chunks are cut at the first game boundary after this many bytes
+const CHUNK_SIZE: usize = 4 << 20;
+
+/// Whether a game starts at `i`: a `[` at the start of a line that follows a
+/// blank line, or like the PGN reader, a header line right after the
+/// movetext of the previous game. Lines starting with `[%`, as wrapped
+/// comments can, are not headers.
+fn is_game_start(data: &[u8], i: usize) -> bool {
+    if i == 0 || data[i - 1] != b'\n' {
+        return false;
+    }
+    let start = memchr::memrchr(b'\n', &data[..i - 1]).map_or(0, |n| n + 1);
+    let previous = &data[start..i - 1];
+    let previous = previous.strip_suffix(b"\r").unwrap_or(previous);
+    previous.is_empty() || (is_header(&data[i..]) && !is_header(previous))
+}
+
+/// Whether a line starts like a header, `[` and a letter
+fn is_header(line: &[u8]) -> bool {
+    line.starts_with(b"[") && line.get(1).is_some_and(u8::is_ascii_alphabetic)
+}
+
+/// Finds the start of the last game in `data`. The blank line before it stays
+/// with the previous chunk.
+fn last_game_start(data: &[u8]) -> Option<usize> {
+    let mut end = data.len();
+    while let Some(i) = memchr::memrchr(b'[', &data[..end]) {
+        if is_game_start(data, i) {
+            return Some(i);
+        }
+        end = i;
+    }
+    None
+}
+
+/// Finds where each game of a chunk starts, using the same boundaries as
+/// [`last_game_start`]. The first game starts after any whitespace or BOM.
+fn game_starts(data: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    let first = data
+        .iter()
+        .position(|&b| !b.is_ascii_whitespace() && b != 0xef && b != 0xbb && b != 0xbf)
+        .unwrap_or(0);
+    iter::once(first).chain(
+        memchr::memchr_iter(b'[', data).filter(move |&i| i > first && is_game_start(data, i)),
+    )
+}
+
+/// Decompressed games, cut at a game boundary
+struct Chunk {
+    /// Byte offset of the chunk in the decompressed input
+    offset: u64,
+    data: Vec<u8>,
+}
+
+/// Reads `reader` to the end in blocks, sending them to the splitter
+fn decompress(mut reader: impl Read, blocks: mpsc::SyncSender<Vec<u8>>) -> io::Result<()> {
+    loop {
+        let mut block = Vec::with_capacity(BLOCK_SIZE);
+        let read = reader
+            .by_ref()
+            .take(BLOCK_SIZE as u64)
+            .read_to_end(&mut block)?;
+        if read == 0 || blocks.send(block).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Reassembles blocks into chunks that only contain whole games
+fn split(blocks: mpsc::Receiver<Vec<u8>>, chunks: mpsc::SyncSender<Chunk>) {
+    let mut offset = 0;
+    let mut pending = Vec::with_capacity(CHUNK_SIZE + BLOCK_SIZE);
+    for block in blocks {
+        pending.extend_from_slice(&block);
+        if pending.len() < CHUNK_SIZE {
+            continue;
+        }
+        if let Some(start) = last_game_start(&pending) {
+            let rest = pending.split_off(start);
+            let data = mem::replace(&mut pending, rest);
+            let next_offset = offset + data.len() as u64;
+            if chunks.send(Chunk { offset, data }).is_err() {
+                return;
+            }
+            offset = next_offset;
+        }
+    }
+    if !pending.is_empty() {
+        let _ = chunks.send(Chunk {
+            offset,
+            data: pending,
+        });
+    }
+}
+
+/// Merges counts from all workers and prints progress every 100000 games
+struct Progress<'a> {
+    name: &'a str,
+    stats: Mutex<Stats>,
+    time: Instant,
+}
+
+impl Progress<'_> {
+    fn add(&self, chunk: &Stats) {
+        let mut stats = self.stats.lock().expect("progress poisoned");
+        let before = stats.games / 100_000;
+        *stats += chunk;
+        if stats.games / 100_000 == before {
+            return;
+        }
+        eprintln!(
+            "{}: {} games, {} il vaticanos, {} positions, {} passed, {:.5}% positions {:.5}% games",
+            self.name,
+            stats.games,
+            stats.ilvaticanos,
+            stats.positions(),
+            stats.passed,
+            (stats.ilvaticanos as f32 / stats.positions() as f32) * 100.0,
+            (stats.ilvaticanos as f32 / stats.games as f32) * 100.0
+        );
+        eprintln!(
+            "{}: took {} ms.",
+            self.name,
+            self.time.elapsed().as_millis()
+        );
+    }
+}
+
+/// Runs `counter` over every game of `chunk`. Games the PGN reader rejects
+/// are counted as invalid instead of stopping the whole input.
+fn count_chunk(counter: &mut IlVaticanoCounter, chunk: &Chunk) {
+    counter.begin_chunk();
+    let invalid_before = counter.invalid.len();
+
+    let mut reader = BufferedReader::new_cursor(&chunk.data[..]);
+    loop {
+        match reader.read_game(counter) {
+            Ok(Some(())) => {}
+            Ok(None) => break,
+            Err(err) => counter.abort_game(&err),
+        }
+    }
+
+    // Invalid games are in the order they were read, so the game boundaries
+    // are walked once per chunk
+    let mut starts = game_starts(&chunk.data);
+    let (mut n, mut start) = (0, starts.next().unwrap_or(0));
+    for game in &mut counter.invalid[invalid_before..] {
+        while n < game.chunk_game {
+            let Some(next) = starts.next() else {
+                break;
+            };
+            (n, start) = (n + 1, next);
+        }
+        game.offset = chunk.offset + start as u64;
+    }
+}
+
+/// Counts il vaticanos in `reader`, with decompression, splitting and
+/// `threads` counting workers each running on their own thread.
+/// Returns the counters of all workers merged into one.
+pub fn run<'a>(
+    reader: Box<dyn Read + Send>,
+    name: &str,
+    threads: usize,
+    new_counter: impl Fn() -> IlVaticanoCounter<'a> + Sync,
+) -> io::Result<IlVaticanoCounter<'a>> {
+    let progress = Progress {
+        name,
+        stats: Mutex::new(Stats::default()),
+        time: Instant::now(),
+    };
+    let (block_tx, block_rx) = mpsc::sync_channel(4);
+    let (chunk_tx, chunk_rx) = mpsc::sync_channel(threads * 2);
+    // Dropped once the workers are done, so the splitter stops even if they
+    // stopped early
+    let chunk_rx = Mutex::new(Some(chunk_rx));
+    let failed = AtomicBool::new(false);
+
+    let mut merged = new_counter();
+    thread::scope(|scope| {
+        let decompressor = scope.spawn(move || decompress(reader, block_tx));
+        let splitter = scope.spawn(move || split(block_rx, chunk_tx));
+
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut counter = new_counter();
+                    loop {
+                        if failed.load(Ordering::Relaxed) {
+                            return counter;
+                        }
+                        let chunk = chunk_rx
+                            .lock()
+                            .expect("chunk queue poisoned")
+                            .as_ref()
+                            .map(|chunks| chunks.recv());
+                        let Some(Ok(chunk)) = chunk else {
+                            return counter;
+                        };
+                        count_chunk(&mut counter, &chunk);
+                        progress.add(&mem::take(&mut counter.stats));
+                        // A failed write fails the whole input
+                        if counter.has_error() {
+                            failed.store(true, Ordering::Relaxed);
+                            return counter;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            merged.merge(worker.join().expect("worker panicked"));
+        }
+        chunk_rx.lock().expect("chunk queue poisoned").take();
+        splitter.join().expect("splitter panicked");
+        decompressor.join().expect("decompressor panicked")
+    })?;
+
+    if let Some(err) = merged.take_error() {
+        return Err(err);
+    }
+    merged.stats = progress.stats.into_inner().expect("progress poisoned");
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{counter::Outputs, options::Options};
+
+    const GAME: &str = "[Event \"Rated Blitz game\"]\n[Site \"?\"]\n\n1. e4 e5 2. Nf3 Nc6 1-0\n\n";
+
+    #[test]
+    fn finds_game_starts() {
+        let data = format!("{GAME}{GAME}{GAME}");
+        let len = GAME.len();
+        assert_eq!(last_game_start(data.as_bytes()), Some(2 * len));
+        assert_eq!(
+            game_starts(data.as_bytes()).collect::<Vec<_>>(),
+            [0, len, 2 * len]
+        );
+
+        // The `[` of the first game and of headers is not a boundary
+        assert_eq!(last_game_start(GAME.as_bytes()), None);
+
+        let crlf = format!("{GAME}{GAME}").replace('\n', "\r\n");
+        let len = GAME.len() + GAME.matches('\n').count();
+        assert_eq!(last_game_start(crlf.as_bytes()), Some(len));
+        assert_eq!(game_starts(crlf.as_bytes()).collect::<Vec<_>>(), [0, len]);
+
+        // Leading blank lines and a BOM belong to the first game
+        let bom = format!("\u{feff}\n\n{GAME}{GAME}");
+        assert_eq!(
+            game_starts(bom.as_bytes()).collect::<Vec<_>>(),
+            [5, 5 + GAME.len()]
+        );
+        assert_eq!(game_starts(b"").collect::<Vec<_>>(), [0]);
+
+        // A single newline between games, but not between headers or before
+        // a wrapped comment
+        let data = b"[Event \"a\"]\n[Site \"?\"]\n\n1. e4 { a\n[%clk 0:03:00] } 1-0\n[Event \"b\"]\n\n1. d4 *\r\n[Event \"c\"]\n";
+        let starts: Vec<_> = game_starts(data).collect();
+        assert_eq!(starts.len(), 3);
+        assert!(starts[1..]
+            .iter()
+            .all(|&i| data[i..].starts_with(b"[Event")));
+        assert_eq!(last_game_start(data), Some(starts[2]));
+    }
+
+    /// Splits `blocks` into chunks and checks that they add up to the input,
+    /// each one holding whole games
+    fn check_split(blocks: Vec<Vec<u8>>) -> Vec<Chunk> {
+        let input = blocks.concat();
+        let (block_tx, block_rx) = mpsc::sync_channel(blocks.len());
+        for block in blocks {
+            block_tx.send(block).unwrap();
+        }
+        drop(block_tx);
+        let (chunk_tx, chunk_rx) = mpsc::sync_channel(input.len() / CHUNK_SIZE + 1);
+        split(block_rx, chunk_tx);
+
+        let chunks: Vec<_> = chunk_rx.into_iter().collect();
+        let mut offset = 0;
+        for chunk in &chunks {
+            assert_eq!(chunk.offset, offset);
+            assert!(chunk.data.starts_with(b"[Event"));
+            assert!(chunk.data.ends_with(b"\n"));
+            offset += chunk.data.len() as u64;
+        }
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|chunk| &chunk.data[..])
+                .collect::<Vec<_>>()
+                .concat(),
+            input
+        );
+        chunks
+    }
+
+    #[test]
+    fn splits_at_game_boundaries() {
+        let games = GAME.repeat(CHUNK_SIZE / GAME.len() + 1).into_bytes();
+
+        // A block ending in the middle of the blank line before a game
+        let cut = games.len() - GAME.len() - 1;
+        let chunks = check_split(vec![games[..cut].to_vec(), games[cut..].to_vec()]);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1].offset as usize, games.len() - GAME.len());
+
+        // A game starting at the last byte before the chunk is full
+        let mut first = GAME.repeat(CHUNK_SIZE / GAME.len() - 1).into_bytes();
+        first.resize(CHUNK_SIZE - 1, b'\n');
+        first.push(b'[');
+        let chunks = check_split(vec![first, GAME.as_bytes()[1..].to_vec()]);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[1].offset as usize, CHUNK_SIZE - 1);
+
+        // Games with no blank line between them, which the PGN reader accepts
+        let game = GAME.trim_end().to_owned() + "\n";
+        let games = game.repeat(CHUNK_SIZE / game.len() * 2).into_bytes();
+        let blocks = games.chunks(BLOCK_SIZE).map(<[u8]>::to_vec).collect();
+        let chunks = check_split(blocks);
+        assert_eq!(chunks.len(), 2);
+    }
+
+    #[test]
+    fn locates_invalid_games() {
+        let bad = "[Event \"Rated Blitz game\"]\n\n1. e4 e5 2. Ke3 *\n\n";
+        let data = format!("{GAME}{bad}{GAME}{bad}{bad}");
+        let options = Options::parse(iter::empty()).unwrap();
+        let outputs = Outputs::default();
+        let mut counter = IlVaticanoCounter::new(&options, &outputs);
+        count_chunk(
+            &mut counter,
+            &Chunk {
+                offset: 100,
+                data: data.into_bytes(),
+            },
+        );
+
+        let offsets: Vec<_> = counter.invalid.iter().map(|game| game.offset).collect();
+        let (game, bad) = (GAME.len() as u64, bad.len() as u64);
+        assert_eq!(
+            offsets,
+            [100 + game, 100 + 2 * game + bad, 100 + 2 * game + 2 * bad]
+        );
+    }
+}
@@ -0,0 +1,520 @@
+use std::{ops::RangeInclusive, str::FromStr};
+
+use shakmaty::{Bitboard, Board, Color, File, Move, Position, Rank, Role, Square};
+
+/// Pieces a placement is about, like `B`, `[Pp]`, `A` for any white piece,
+/// `_` for an empty square or `?` for any square
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pieces {
+    /// Bit `color * 6 + role - 1` for each piece
+    pieces: u16,
+    empty: bool,
+}
+
+impl Pieces {
+    fn from_char(c: char) -> Option<Pieces> {
+        let bit = |color: Color, role: Role| 1 << (color as u16 * 6 + role as u16 - 1);
+        let of_color = |color| {
+            Role::ALL
+                .iter()
+                .fold(0, |pieces, &role| pieces | bit(color, role))
+        };
+        let (pieces, empty) = match c {
+            'A' => (of_color(Color::White), false),
+            'a' => (of_color(Color::Black), false),
+            '_' => (0, true),
+            '?' => (of_color(Color::White) | of_color(Color::Black), true),
+            _ => {
+                let role = Role::from_char(c.to_ascii_lowercase())?;
+                (bit(Color::from_white(c.is_ascii_uppercase()), role), false)
+            }
+        };
+        Some(Pieces { pieces, empty })
+    }
+
+    /// The squares holding one of the pieces
+    fn mask(self, board: &Board) -> Bitboard {
+        let mut mask = if self.empty {
+            !board.occupied()
+        } else {
+            Bitboard(0)
+        };
+        let mut pieces = self.pieces;
+        while pieces != 0 {
+            let bit = pieces.trailing_zeros() as usize;
+            pieces &= pieces - 1;
+            mask |= board.by_piece(Role::ALL[bit % 6].of(Color::from_white(bit / 6 == 1)));
+        }
+        mask
+    }
+
+    fn union(self, other: Pieces) -> Pieces {
+        Pieces {
+            pieces: self.pieces | other.pieces,
+            empty: self.empty || other.empty,
+        }
+    }
+}
+
+/// Some of `pieces` standing on `squares`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placement {
+    pieces: Pieces,
+    squares: Bitboard,
+}
+
+impl Placement {
+    fn matches(self, board: &Board) -> bool {
+        (self.pieces.mask(board) & self.squares).any()
+    }
+}
+
+/// Placements relative to each other, compiled to shifted masks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shift {
+    /// Squares the first placement can be moved to
+    anchors: Bitboard,
+    /// Pieces and their distance in squares from the first placement
+    offsets: Vec<(Pieces, i32)>,
+}
+
+impl Shift {
+    fn matches(&self, board: &Board) -> bool {
+        // Moves each mask back by its offset, so that the bits left over are
+        // the anchors every placement agrees on. Anchors never wrap around the
+        // edges of the board.
+        let mut anchors = self.anchors;
+        for &(pieces, offset) in &self.offsets {
+            let Bitboard(mask) = pieces.mask(board);
+            anchors &= Bitboard(if offset >= 0 {
+                mask >> offset
+            } else {
+                mask << -offset
+            });
+            if anchors.is_empty() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    GreaterOrEqual,
+    Greater,
+}
+
+/// A compiled query, matched against a position and the move played from it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Query {
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+    Placement(Placement),
+    /// All placements at once, moved together anywhere on the board
+    Shift(Shift),
+    Count(Placement, Comparison, u32),
+    WhiteToMove,
+    BlackToMove,
+    Check,
+    MoveFrom(Placement),
+    MoveTo(Placement),
+    Capture,
+}
+
+impl Query {
+    /// Whether the position, before `m` is played from it, matches. Move
+    /// conditions never match without a move.
+    pub fn matches(&self, pos: &impl Position, m: Option<&Move>) -> bool {
+        let board = pos.board();
+        match self {
+            Query::And(queries) => queries.iter().all(|query| query.matches(pos, m)),
+            Query::Or(queries) => queries.iter().any(|query| query.matches(pos, m)),
+            Query::Not(query) => !query.matches(pos, m),
+            Query::Placement(placement) => placement.matches(board),
+            Query::Shift(shift) => shift.matches(board),
+            Query::Count(placement, comparison, n) => {
+                let count = (placement.pieces.mask(board) & placement.squares).count() as u32;
+                match comparison {
+                    Comparison::Less => count < *n,
+                    Comparison::LessOrEqual => count <= *n,
+                    Comparison::Equal => count == *n,
+                    Comparison::GreaterOrEqual => count >= *n,
+                    Comparison::Greater => count > *n,
+                }
+            }
+            Query::WhiteToMove => pos.turn() == Color::White,
+            Query::BlackToMove => pos.turn() == Color::Black,
+            Query::Check => pos.is_check(),
+            Query::MoveFrom(placement) => m.and_then(Move::from).is_some_and(|from| {
+                (placement.pieces.mask(board) & placement.squares).contains(from)
+            }),
+            Query::MoveTo(placement) => m.is_some_and(|m| {
+                (placement.pieces.mask(board) & placement.squares).contains(m.to())
+            }),
+            Query::Capture => m.is_some_and(Move::is_capture),
+        }
+    }
+}
+
+/// Recursive descent over the query text
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+const KEYWORDS: [&str; 11] = [
+    "and", "or", "not", "wtm", "btm", "check", "capture", "shift", "count", "movefrom", "moveto",
+];
+
+impl Parser<'_> {
+    fn error(&self, expected: &str) -> String {
+        format!(
+            "expected {} at column {} of query {}",
+            expected,
+            self.pos + 1,
+            self.text
+        )
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.text[self.pos..].chars().next()
+    }
+
+    /// Consumes `token` if it comes next
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.text[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("`{}`", token)))
+        }
+    }
+
+    /// The keyword starting at the current position, if any
+    fn keyword(&mut self) -> Option<&'static str> {
+        self.skip_whitespace();
+        let rest = &self.text[self.pos..];
+        let word = &rest[..rest
+            .find(|c: char| !c.is_ascii_lowercase())
+            .unwrap_or(rest.len())];
+        KEYWORDS.iter().copied().find(|&keyword| keyword == word)
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.keyword() == Some(keyword) {
+            self.pos += keyword.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<Query, String> {
+        let mut queries = vec![self.and()?];
+        while self.eat_keyword("or") {
+            queries.push(self.and()?);
+        }
+        Ok(if queries.len() == 1 {
+            queries.pop().expect("one query")
+        } else {
+            Query::Or(queries)
+        })
+    }
+
+    /// Terms next to each other have to match together, with or without `and`
+    fn and(&mut self) -> Result<Query, String> {
+        let mut queries = vec![self.unary()?];
+        loop {
+            let and = self.eat_keyword("and");
+            self.skip_whitespace();
+            if self.peek().is_none_or(|c| c == ')') || self.keyword() == Some("or") {
+                if and {
+                    return Err(self.error("a term after `and`"));
+                }
+                break;
+            }
+            queries.push(self.unary()?);
+        }
+        Ok(if queries.len() == 1 {
+            queries.pop().expect("one query")
+        } else {
+            Query::And(queries)
+        })
+    }
+
+    fn unary(&mut self) -> Result<Query, String> {
+        if self.eat_keyword("not") {
+            return Ok(Query::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let query = self.or()?;
+            self.expect(")")?;
+            return Ok(query);
+        }
+        let Some(keyword) = self.keyword() else {
+            return Ok(Query::Placement(self.placement()?));
+        };
+        self.pos += keyword.len();
+        Ok(match keyword {
+            "wtm" => Query::WhiteToMove,
+            "btm" => Query::BlackToMove,
+            "check" => Query::Check,
+            "capture" => Query::Capture,
+            "movefrom" => Query::MoveFrom(self.placement()?),
+            "moveto" => Query::MoveTo(self.placement()?),
+            "shift" => self.shift()?,
+            "count" => {
+                self.expect("(")?;
+                let placement = self.placement()?;
+                self.expect(")")?;
+                let comparison = [
+                    ("<=", Comparison::LessOrEqual),
+                    (">=", Comparison::GreaterOrEqual),
+                    ("==", Comparison::Equal),
+                    ("<", Comparison::Less),
+                    (">", Comparison::Greater),
+                ]
+                .into_iter()
+                .find(|(token, _)| self.eat(token))
+                .map(|(_, comparison)| comparison)
+                .ok_or_else(|| self.error("a comparison"))?;
+                Query::Count(placement, comparison, self.number()?)
+            }
+            _ => return Err(self.error("a term")),
+        })
+    }
+
+    fn number(&mut self) -> Result<u32, String> {
+        self.skip_whitespace();
+        let rest = &self.text[self.pos..];
+        let len = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let number = rest[..len].parse().map_err(|_| self.error("a number"))?;
+        self.pos += len;
+        Ok(number)
+    }
+
+    /// `shift { ... }`: placements of single squares, moved together in any
+    /// direction
+    fn shift(&mut self) -> Result<Query, String> {
+        self.expect("{")?;
+        let mut placements = Vec::new();
+        while !self.eat("}") {
+            let placement = self.placement()?;
+            if placement.squares.count() != 1 {
+                return Err(self.error("a single square in shift"));
+            }
+            placements.push(placement);
+        }
+        if placements.is_empty() {
+            return Err(self.error("a placement in shift"));
+        }
+
+        // Offsets from the first placement, and the squares it can be moved
+        // to with all the others still on the board
+        let first = placements[0].squares.first().expect("one square");
+        let mut anchors = Bitboard::FULL;
+        let mut offsets = Vec::new();
+        for placement in &placements {
+            let square = placement.squares.first().expect("one square");
+            let file_offset = i32::from(square.file()) - i32::from(first.file());
+            let rank_offset = i32::from(square.rank()) - i32::from(first.rank());
+            anchors &= Bitboard::from_iter(Square::ALL.into_iter().filter(|anchor| {
+                File::try_from(i32::from(anchor.file()) + file_offset).is_ok()
+                    && Rank::try_from(i32::from(anchor.rank()) + rank_offset).is_ok()
+            }));
+            offsets.push((placement.pieces, file_offset + 8 * rank_offset));
+        }
+        Ok(Query::Shift(Shift { anchors, offsets }))
+    }
+
+    /// Pieces followed by squares, like `Ba-h4` or `[Pp][e4,d5]`. Without
+    /// squares the whole board is meant.
+    fn placement(&mut self) -> Result<Placement, String> {
+        self.skip_whitespace();
+        let pieces = if self.eat("[") {
+            let mut pieces = Pieces {
+                pieces: 0,
+                empty: false,
+            };
+            while !self.eat("]") {
+                let c = self.peek().ok_or_else(|| self.error("`]`"))?;
+                pieces = pieces.union(Pieces::from_char(c).ok_or_else(|| self.error("a piece"))?);
+                self.pos += c.len_utf8();
+            }
+            pieces
+        } else {
+            let c = self.peek().ok_or_else(|| self.error("a piece"))?;
+            let pieces = Pieces::from_char(c).ok_or_else(|| self.error("a piece"))?;
+            self.pos += c.len_utf8();
+            pieces
+        };
+
+        let squares = if self.text[self.pos..].starts_with('[') {
+            self.pos += 1;
+            let mut squares = Bitboard(0);
+            loop {
+                squares |= self.squares()?;
+                if self.eat("]") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+            squares
+        } else if self.peek().is_some_and(|c| ('a'..='h').contains(&c)) {
+            self.squares()?
+        } else {
+            Bitboard::FULL
+        };
+        Ok(Placement { pieces, squares })
+    }
+
+    /// A file or rank, or a range of them like `a-h`
+    fn range(
+        &mut self,
+        parse: fn(char) -> Option<u32>,
+        expected: &str,
+    ) -> Result<RangeInclusive<u32>, String> {
+        let first = self
+            .peek()
+            .and_then(parse)
+            .ok_or_else(|| self.error(expected))?;
+        self.pos += 1;
+        if !self.text[self.pos..].starts_with('-') {
+            return Ok(first..=first);
+        }
+        self.pos += 1;
+        let last = self
+            .peek()
+            .and_then(parse)
+            .ok_or_else(|| self.error(expected))?;
+        self.pos += 1;
+        Ok(first.min(last)..=first.max(last))
+    }
+
+    /// A square or a range of them, like `e4`, `a-h4` or `d-f2-7`
+    fn squares(&mut self) -> Result<Bitboard, String> {
+        self.skip_whitespace();
+        let files = self.range(
+            |c| ('a'..='h').contains(&c).then(|| c as u32 - 'a' as u32),
+            "a file",
+        )?;
+        let ranks = self.range(
+            |c| ('1'..='8').contains(&c).then(|| c as u32 - '1' as u32),
+            "a rank",
+        )?;
+        let mut squares = Bitboard(0);
+        for file in files {
+            for rank in ranks.clone() {
+                squares.add(Square::from_coords(File::new(file), Rank::new(rank)));
+            }
+        }
+        Ok(squares)
+    }
+}
+
+impl FromStr for Query {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Query, String> {
+        let mut parser = Parser { text, pos: 0 };
+        let query = parser.or()?;
+        parser.skip_whitespace();
+        if parser.pos < text.len() {
+            return Err(parser.error("the end"));
+        }
+        Ok(query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::{fen::Fen, CastlingMode, Chess};
+
+    use super::*;
+
+    fn position(fen: &str) -> Chess {
+        Fen::from_ascii(fen.as_bytes())
+            .unwrap()
+            .into_position(CastlingMode::Standard)
+            .unwrap()
+    }
+
+    #[test]
+    fn matches_il_vaticano() {
+        let query: Query = "wtm shift { Ba2 pb2 pc2 Bd2 } or btm shift { ba2 Pb2 Pc2 bd2 }"
+            .parse()
+            .unwrap();
+        let found = position("rnbqkbnr/1p2pppp/8/p7/1BppB3/1P2P3/P1PP1PPP/RN1QK1NR w KQkq - 0 7");
+        let latent = position("rnbqkbnr/1p2pppp/8/p7/1BppB3/1P2P3/P1PP1PPP/RN1QK1NR b KQkq - 0 7");
+        assert!(query.matches(&found, None));
+        assert!(!query.matches(&latent, None));
+        assert!(!query.matches(&Chess::default(), None));
+    }
+
+    #[test]
+    fn matches_terms() {
+        let pos = position("rnbqkbnr/1p2pppp/8/p7/1BppB3/1P2P3/P1PP1PPP/RN1QK1NR w KQkq - 0 7");
+        let matches =
+            |text: &str, m: Option<&Move>| text.parse::<Query>().unwrap().matches(&pos, m);
+        assert!(matches("Bd-f2-7", None));
+        assert!(matches("[Bb]b4 _c5", None));
+        assert!(matches("count(B) == 2 and count(p[c4,d4]) >= 2", None));
+        assert!(!matches("count(P) > 8", None));
+        assert!(matches("not check and not btm", None));
+        assert!(!matches("(check or btm) Bb4", None));
+        assert!(matches("A?", None));
+
+        let m = Move::Normal {
+            role: Role::Bishop,
+            from: Square::B4,
+            capture: Some(Role::Pawn),
+            to: Square::E7,
+            promotion: None,
+        };
+        assert!(matches("movefrom Bb4 moveto pa-h7 capture", Some(&m)));
+        assert!(!matches("moveto _", Some(&m)));
+        assert!(!matches("capture", None));
+    }
+
+    #[test]
+    fn rejects_bad_queries() {
+        for text in [
+            "",
+            "X",
+            "Bi4",
+            "Ba9",
+            "count(B) 2",
+            "shift { Ba-h2 }",
+            "(wtm",
+            "wtm)",
+            "wtm and",
+            "(wtm and) or btm",
+            "wtm and or btm",
+            "wtm or",
+        ] {
+            assert!(text.parse::<Query>().is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,667 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    iter,
+    ops::AddAssign,
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::{
+    breakdown::{Breakdowns, Dimension},
+    counter::{InvalidGame, PerformedGame},
+    detector::{self, Counts},
+};
+
+/// Bumped whenever a field is renamed, removed or changes meaning
+pub const SCHEMA_VERSION: u32 = 3;
+
+/// Output format of the final summary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+    Csv,
+    Ndjson,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        Some(match name {
+            "text" => Format::Text,
+            "json" => Format::Json,
+            "csv" => Format::Csv,
+            "ndjson" => Format::Ndjson,
+            _ => return None,
+        })
+    }
+}
+
+/// The counters kept by `IlVaticanoCounter`
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Stats {
+    pub games: usize,
+    pub sans: usize,
+    /// Final positions checked with `--final-position`, which have no move
+    pub terminal_positions: usize,
+    pub passed: usize,
+    pub ilvaticanos: usize,
+    pub invalid_games: usize,
+    /// Games skipped because they didn't match the filters
+    pub filtered_games: usize,
+    /// Il vaticanos of the side not to move, counted with `--both-colors`
+    pub latent_ilvaticanos: usize,
+    /// Il vaticanos that would not leave the mover's king attacked
+    pub legal_ilvaticanos: usize,
+    /// Legal il vaticanos that still win material after the exchanges
+    pub good_ilvaticanos: usize,
+    /// Il vaticanos played over the board, both bishops taking a pawn
+    pub performed_ilvaticanos: usize,
+    /// The counts of each detector by its name, like `patterns` for the
+    /// positions with each `--pattern`
+    #[serde(flatten)]
+    pub detectors: BTreeMap<String, Counts>,
+}
+
+impl Stats {
+    /// Every position checked, the rate of il vaticanos per position is based on
+    pub fn positions(&self) -> usize {
+        self.sans + self.terminal_positions
+    }
+}
+
+/// Adds counts by name to `counts`, without allocating for known names
+fn add_counts(counts: &mut Counts, other: &Counts) {
+    for (name, count) in other {
+        match counts.get_mut(name) {
+            Some(total) => *total += count,
+            None => {
+                counts.insert(name.clone(), *count);
+            }
+        }
+    }
+}
+
+impl AddAssign<&Stats> for Stats {
+    fn add_assign(&mut self, other: &Stats) {
+        self.games += other.games;
+        self.sans += other.sans;
+        self.terminal_positions += other.terminal_positions;
+        self.passed += other.passed;
+        self.ilvaticanos += other.ilvaticanos;
+        self.invalid_games += other.invalid_games;
+        self.filtered_games += other.filtered_games;
+        self.latent_ilvaticanos += other.latent_ilvaticanos;
+        self.legal_ilvaticanos += other.legal_ilvaticanos;
+        self.good_ilvaticanos += other.good_ilvaticanos;
+        self.performed_ilvaticanos += other.performed_ilvaticanos;
+        for (name, counts) in &other.detectors {
+            match self.detectors.get_mut(name) {
+                Some(total) => add_counts(total, counts),
+                None => {
+                    self.detectors.insert(name.clone(), counts.clone());
+                }
+            }
+        }
+    }
+}
+
+fn percentage(count: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 / total as f64 * 100.0
+    }
+}
+
+/// The result of processing one input
+#[derive(Debug)]
+pub struct Summary {
+    pub input: String,
+    pub stats: Stats,
+    pub invalid: Vec<InvalidGame>,
+    pub performed: Vec<PerformedGame>,
+    pub breakdowns: Breakdowns,
+    pub elapsed: Duration,
+}
+
+/// The stats of one group of a breakdown
+#[derive(Serialize)]
+struct Group<'a> {
+    key: &'a str,
+    #[serde(flatten)]
+    stats: &'a Stats,
+    position_pct: f64,
+    game_pct: f64,
+    /// Places by `game_pct` and `position_pct`, for ranked dimensions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    game_rank: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    position_rank: Option<usize>,
+}
+
+impl<'a> Group<'a> {
+    fn new(key: &'a str, stats: &'a Stats) -> Group<'a> {
+        Group {
+            key,
+            stats,
+            position_pct: percentage(stats.ilvaticanos, stats.positions()),
+            game_pct: percentage(stats.ilvaticanos, stats.games),
+            game_rank: None,
+            position_rank: None,
+        }
+    }
+}
+
+/// Ranks groups by their il vaticano rates, ordering them by the rate per game
+fn rank(groups: &mut [Group]) {
+    groups.sort_by(|a, b| b.position_pct.total_cmp(&a.position_pct));
+    for (place, group) in groups.iter_mut().enumerate() {
+        group.position_rank = Some(place + 1);
+    }
+    groups.sort_by(|a, b| b.game_pct.total_cmp(&a.game_pct));
+    for (place, group) in groups.iter_mut().enumerate() {
+        group.game_rank = Some(place + 1);
+    }
+}
+
+#[derive(Serialize)]
+struct Row<'a> {
+    kind: &'static str,
+    input: Option<&'a str>,
+    #[serde(flatten)]
+    stats: &'a Stats,
+    position_pct: f64,
+    game_pct: f64,
+    elapsed_ms: u128,
+    /// Only listed per input, not in the total
+    #[serde(skip_serializing_if = "Option::is_none")]
+    invalid: Option<&'a [InvalidGame]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    performed: Option<&'a [PerformedGame]>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    breakdowns: BTreeMap<Dimension, Vec<Group<'a>>>,
+}
+
+impl<'a> Row<'a> {
+    fn new(
+        kind: &'static str,
+        input: Option<&'a str>,
+        stats: &'a Stats,
+        invalid: Option<&'a [InvalidGame]>,
+        performed: Option<&'a [PerformedGame]>,
+        breakdowns: &'a Breakdowns,
+        elapsed: Duration,
+    ) -> Row<'a> {
+        Row {
+            kind,
+            input,
+            stats,
+            position_pct: percentage(stats.ilvaticanos, stats.positions()),
+            game_pct: percentage(stats.ilvaticanos, stats.games),
+            elapsed_ms: elapsed.as_millis(),
+            invalid,
+            performed,
+            breakdowns: breakdowns
+                .0
+                .keys()
+                .map(|&dimension| {
+                    let mut groups: Vec<_> = breakdowns
+                        .groups(dimension)
+                        .into_iter()
+                        .map(|(key, stats)| Group::new(key, stats))
+                        .collect();
+                    if dimension.ranked() {
+                        rank(&mut groups);
+                    }
+                    (dimension, groups)
+                })
+                .collect(),
+        }
+    }
+
+    /// The row itself, followed by a row for each group of its breakdowns
+    fn csv(&self) -> Vec<String> {
+        let rank = |rank: Option<usize>| rank.map_or(String::new(), |rank| rank.to_string());
+        let line = |kind: &str, group: &Group, elapsed: &str| {
+            let mut line = format!(
+                "{},{},{},{},{},{},{},{:.5},{:.5},{},{},{},{},{},{},{},{},{},{},{}",
+                SCHEMA_VERSION,
+                kind,
+                csv_field(self.input.unwrap_or("")),
+                group.stats.games,
+                group.stats.sans,
+                group.stats.passed,
+                group.stats.ilvaticanos,
+                group.position_pct,
+                group.game_pct,
+                elapsed,
+                group.stats.invalid_games,
+                csv_field(group.key),
+                rank(group.game_rank),
+                rank(group.position_rank),
+                group.stats.filtered_games,
+                group.stats.latent_ilvaticanos,
+                group.stats.legal_ilvaticanos,
+                group.stats.good_ilvaticanos,
+                group.stats.performed_ilvaticanos,
+                group.stats.terminal_positions
+            );
+            for name in detector::names() {
+                line.push(',');
+                line.push_str(&counts_field(group.stats.detectors.get(name)));
+            }
+            line
+        };
+        let mut lines = vec![line(
+            self.kind,
+            &Group::new("", self.stats),
+            &self.elapsed_ms.to_string(),
+        )];
+        for (dimension, groups) in &self.breakdowns {
+            for group in groups {
+                lines.push(line(dimension.name(), group, ""));
+            }
+        }
+        lines
+    }
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    schema_version: u32,
+    files: Vec<Row<'a>>,
+    total: Row<'a>,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    schema_version: u32,
+    #[serde(flatten)]
+    row: Row<'a>,
+}
+
+/// The CSV columns before a column for each detector
+const CSV_COLUMNS: &str =
+    "schema_version,kind,input,games,sans,passed,ilvaticanos,position_pct,game_pct,elapsed_ms,invalid_games,key,game_rank,position_rank,filtered_games,latent_ilvaticanos,legal_ilvaticanos,good_ilvaticanos,performed_ilvaticanos,terminal_positions";
+
+fn csv_header() -> String {
+    iter::once(CSV_COLUMNS)
+        .chain(detector::names())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Counts by name in one field, like `B-p-B=3; R-nn-R=1`
+fn counts_field(counts: Option<&Counts>) -> String {
+    csv_field(
+        &counts
+            .into_iter()
+            .flatten()
+            .map(|(name, count)| format!("{name}={count}"))
+            .collect::<Vec<_>>()
+            .join("; "),
+    )
+}
+
+/// Writes the per-file summaries and their aggregate in the requested format
+pub fn write_summary(
+    out: &mut impl Write,
+    format: Format,
+    summaries: &[Summary],
+    elapsed: Duration,
+) -> io::Result<()> {
+    let mut total = Stats::default();
+    let mut total_breakdowns = Breakdowns::default();
+    for summary in summaries {
+        total += &summary.stats;
+        total_breakdowns.merge(&summary.breakdowns);
+    }
+
+    let files = summaries.iter().map(|summary| {
+        Row::new(
+            "file",
+            Some(&summary.input),
+            &summary.stats,
+            Some(&summary.invalid),
+            Some(&summary.performed),
+            &summary.breakdowns,
+            summary.elapsed,
+        )
+    });
+    let total = Row::new(
+        "total",
+        None,
+        &total,
+        None,
+        None,
+        &total_breakdowns,
+        elapsed,
+    );
+
+    match format {
+        Format::Text => {
+            for row in files.chain([total]) {
+                writeln!(
+                    out,
+                    "{}: {} games, {} il vaticanos, {} positions, {} passed, {:.5}% positions {:.5}% games, {} invalid games, took {} ms.",
+                    row.input.unwrap_or("total"),
+                    row.stats.games,
+                    row.stats.ilvaticanos,
+                    row.stats.positions(),
+                    row.stats.passed,
+                    row.position_pct,
+                    row.game_pct,
+                    row.stats.invalid_games,
+                    row.elapsed_ms
+                )?;
+                if row.stats.ilvaticanos > 0 {
+                    writeln!(
+                        out,
+                        "  {} of the il vaticanos would be legal moves, {} winning material",
+                        row.stats.legal_ilvaticanos, row.stats.good_ilvaticanos
+                    )?;
+                }
+                if row.stats.performed_ilvaticanos > 0 {
+                    writeln!(
+                        out,
+                        "  {} il vaticanos played over the board",
+                        row.stats.performed_ilvaticanos
+                    )?;
+                }
+                for game in row.performed.unwrap_or_default() {
+                    let headers = &game.headers;
+                    writeln!(
+                        out,
+                        "  performed in {} ({} vs {}) by {}, plies {} and {}: B{} {} {} B{}",
+                        headers.name(),
+                        headers.white,
+                        headers.black,
+                        game.color,
+                        game.first_ply,
+                        game.second_ply,
+                        game.squares[0],
+                        game.squares[1],
+                        game.squares[2],
+                        game.squares[3]
+                    )?;
+                }
+                for (name, count) in row.stats.detectors.values().flatten() {
+                    writeln!(out, "  {}: {} positions", name, count)?;
+                }
+                if row.stats.latent_ilvaticanos > 0 {
+                    writeln!(
+                        out,
+                        "  {} latent il vaticanos for the side not to move",
+                        row.stats.latent_ilvaticanos
+                    )?;
+                }
+                if row.stats.filtered_games > 0 {
+                    writeln!(out, "  {} games filtered out", row.stats.filtered_games)?;
+                }
+                for game in row.invalid.unwrap_or_default() {
+                    writeln!(
+                        out,
+                        "  invalid game {} at byte {}, ply {}{}: {}",
+                        game.headers.name(),
+                        game.offset,
+                        game.ply,
+                        game.san
+                            .as_ref()
+                            .map_or(String::new(), |san| format!(" ({})", san)),
+                        game.error
+                    )?;
+                }
+                for (dimension, groups) in &row.breakdowns {
+                    for group in groups {
+                        let place = match (group.game_rank, group.position_rank) {
+                            (Some(game), Some(position)) => {
+                                format!("#{} (#{} by positions) ", game, position)
+                            }
+                            _ => String::new(),
+                        };
+                        writeln!(
+                            out,
+                            "  {} {}{}: {} games, {} il vaticanos, {} positions, {} passed, {:.5}% positions {:.5}% games",
+                            dimension.name(),
+                            place,
+                            group.key,
+                            group.stats.games,
+                            group.stats.ilvaticanos,
+                            group.stats.positions(),
+                            group.stats.passed,
+                            group.position_pct,
+                            group.game_pct
+                        )?;
+                    }
+                }
+            }
+        }
+        Format::Json => {
+            let report = Report {
+                schema_version: SCHEMA_VERSION,
+                files: files.collect(),
+                total,
+            };
+            serde_json::to_writer_pretty(&mut *out, &report)?;
+            writeln!(out)?;
+        }
+        Format::Ndjson => {
+            for row in files.chain([total]) {
+                serde_json::to_writer(
+                    &mut *out,
+                    &Line {
+                        schema_version: SCHEMA_VERSION,
+                        row,
+                    },
+                )?;
+                writeln!(out)?;
+            }
+        }
+        Format::Csv => {
+            writeln!(out, "{}", csv_header())?;
+            for row in files.chain([total]) {
+                for line in row.csv() {
+                    writeln!(out, "{}", line)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headers::Headers;
+
+    fn summaries() -> Vec<Summary> {
+        let stats = Stats {
+            games: 4,
+            sans: 200,
+            ilvaticanos: 2,
+            detectors: BTreeMap::from([(
+                "queries".to_owned(),
+                Counts::from([("a, b".to_owned(), 3)]),
+            )]),
+            ..Stats::default()
+        };
+        let mut breakdowns = Breakdowns::default();
+        breakdowns.add(Dimension::Eco, "A00", &stats);
+        breakdowns.add(Dimension::TimeControl, "blitz", &stats);
+        vec![Summary {
+            input: "a.pgn".to_owned(),
+            stats,
+            invalid: vec![InvalidGame {
+                headers: Headers::default(),
+                offset: 5,
+                ply: 7,
+                san: Some("Ke3".to_owned()),
+                error: "illegal san".to_owned(),
+                chunk_game: 0,
+            }],
+            performed: Vec::new(),
+            breakdowns,
+            elapsed: Duration::ZERO,
+        }]
+    }
+
+    fn write(format: Format) -> String {
+        let mut out = Vec::new();
+        write_summary(&mut out, format, &summaries(), Duration::ZERO).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Counts the fields of a CSV line, minding quotes
+    fn fields(line: &str) -> usize {
+        let mut quoted = false;
+        1 + line
+            .chars()
+            .filter(|&c| {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                c == ',' && !quoted
+            })
+            .count()
+    }
+
+    #[test]
+    fn names_invalid_games() {
+        assert!(
+            write(Format::Text).contains("  invalid game ? at byte 5, ply 7 (Ke3): illegal san\n")
+        );
+    }
+
+    #[test]
+    fn writes_csv_rows_like_the_header() {
+        let csv = write(Format::Csv);
+        let mut lines = csv.lines();
+        let header = csv_header();
+        assert_eq!(lines.next(), Some(header.as_str()));
+        assert!(header.ends_with(",terminal_positions,patterns,queries"));
+        let columns = fields(&header);
+        // The file, its two groups, the total and its two groups
+        assert_eq!(lines.clone().count(), 6);
+        for line in lines {
+            assert_eq!(fields(line), columns, "{line}");
+            assert!(line.starts_with(&format!("{SCHEMA_VERSION},")));
+        }
+    }
+
+    fn keys(value: &serde_json::Value) -> Vec<&str> {
+        value
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect()
+    }
+
+    #[test]
+    fn writes_json_schema() {
+        let json: serde_json::Value = serde_json::from_str(&write(Format::Json)).unwrap();
+        assert_eq!(keys(&json), ["files", "schema_version", "total"]);
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        let row = [
+            "breakdowns",
+            "elapsed_ms",
+            "filtered_games",
+            "game_pct",
+            "games",
+            "good_ilvaticanos",
+            "ilvaticanos",
+            "input",
+            "invalid",
+            "invalid_games",
+            "kind",
+            "latent_ilvaticanos",
+            "legal_ilvaticanos",
+            "passed",
+            "performed",
+            "performed_ilvaticanos",
+            "position_pct",
+            "queries",
+            "sans",
+            "terminal_positions",
+        ];
+        assert_eq!(keys(&json["files"][0]), row);
+        // Invalid and performed games are only listed per input
+        let total: Vec<_> = row
+            .into_iter()
+            .filter(|key| !["invalid", "performed"].contains(key))
+            .collect();
+        assert_eq!(keys(&json["total"]), total);
+        assert_eq!(
+            keys(&json["files"][0]["invalid"][0]),
+            ["error", "headers", "offset", "ply", "san"]
+        );
+        assert_eq!(
+            keys(&json["total"]["breakdowns"]["eco"][0]),
+            [
+                "filtered_games",
+                "game_pct",
+                "game_rank",
+                "games",
+                "good_ilvaticanos",
+                "ilvaticanos",
+                "invalid_games",
+                "key",
+                "latent_ilvaticanos",
+                "legal_ilvaticanos",
+                "passed",
+                "performed_ilvaticanos",
+                "position_pct",
+                "position_rank",
+                "queries",
+                "sans",
+                "terminal_positions",
+            ]
+        );
+
+        for line in write(Format::Ndjson).lines() {
+            let line: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(line["schema_version"], SCHEMA_VERSION);
+        }
+    }
+
+    #[test]
+    fn ranks_groups() {
+        let stats = |ilvaticanos, sans| Stats {
+            games: 10,
+            sans,
+            ilvaticanos,
+            ..Stats::default()
+        };
+        let (a, b, c) = (stats(5, 1000), stats(2, 100), stats(8, 4000));
+        let mut groups = vec![
+            Group::new("A00", &a),
+            Group::new("B00", &b),
+            Group::new("C00", &c),
+        ];
+        rank(&mut groups);
+        let ranks: Vec<_> = groups
+            .iter()
+            .map(|group| (group.key, group.game_rank, group.position_rank))
+            .collect();
+        // Listed by the rate per game, with the place by the rate per position
+        assert_eq!(
+            ranks,
+            [
+                ("C00", Some(1), Some(3)),
+                ("A00", Some(2), Some(2)),
+                ("B00", Some(3), Some(1)),
+            ]
+        );
+    }
+}